@@ -0,0 +1,701 @@
+use super::helper::{PollPolicy, PositionFilter, SAVITZKY_GOLAY_WINDOW, SAVITZKY_GOLAY_WINDOWS};
+use super::run::StopConditions;
+use crate::operations::trigger::TriggerLevel;
+use crate::operations::{Channels, Clock, InputRange, Trigger, MIN_CLK_TIME};
+use dotenv::dotenv;
+use std::env;
+use std::os::raw::{c_int, c_short, c_uchar};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// 取り込んだデータの送り先
+#[derive(Debug, Clone, PartialEq)]
+pub struct SinkConfig {
+    /// データをpostするURL
+    pub post_url: Option<String>,
+    /// postする間隔
+    pub post_interval: Duration,
+    /// 生のデータを書き出すファイル
+    pub raw_data_path: Option<String>,
+}
+
+impl Default for SinkConfig {
+    fn default() -> Self {
+        SinkConfig {
+            post_url: None,
+            post_interval: Duration::from_millis(300),
+            raw_data_path: None,
+        }
+    }
+}
+
+/// ステージなどと同期するためのデジタル入出力のビット番号
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyncConfig {
+    /// この入力ビットがHighになるまで取り込みの開始を待つ
+    pub ready_line: Option<u8>,
+    /// ステージの準備を待つ時間
+    pub ready_timeout: Duration,
+    /// 取り込み中はこの出力ビットをHighにする
+    pub scan_line: Option<u8>,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        SyncConfig {
+            ready_line: None,
+            ready_timeout: Duration::from_secs(60),
+            scan_line: None,
+        }
+    }
+}
+
+/// 1回の計測の設定
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunConfig {
+    /// 装置のID
+    pub id: c_short,
+    pub clock: Clock,
+    /// CH1, CH2の入力レンジ
+    pub ranges: (InputRange, InputRange),
+    pub channels: Channels,
+    pub trigger: Trigger,
+    /// トリガより前に取り込んでおくサンプル数
+    pub prelen: c_int,
+    /// ステージの位置に掛けるフィルタ
+    pub filter: PositionFilter,
+    /// 位置ごとにまとめたデータのために最初に確保しておく数
+    pub capacity: usize,
+    pub sinks: SinkConfig,
+    pub sync: SyncConfig,
+    pub stop: StopConditions,
+    pub polling: PollPolicy,
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        RunConfig {
+            id: 0,
+            clock: Clock::Internal(MIN_CLK_TIME),
+            // 入力が+/-10VなのはSR830の仕様
+            ranges: (InputRange::Bipolar10V, InputRange::Bipolar10V),
+            channels: Channels::Both,
+            trigger: Trigger::Software,
+            prelen: 0,
+            filter: PositionFilter::SavitzkyGolay {
+                window: SAVITZKY_GOLAY_WINDOW,
+            },
+            // +/- 3.75μm駆動させたときに精度375nmで取るために必要な領域
+            capacity: 20000,
+            sinks: SinkConfig::default(),
+            sync: SyncConfig::default(),
+            stop: StopConditions::default(),
+            polling: PollPolicy::default(),
+        }
+    }
+}
+
+impl RunConfig {
+    /// `id` の装置で `clock` のクロックを使い、`stop` を満たすまで取り込む設定
+    /// 他の値は `from_env` と同じ
+    pub fn new(id: c_short, clock: Clock, stop: StopConditions) -> Self {
+        RunConfig {
+            id,
+            clock,
+            stop,
+            ..RunConfig::from_env()
+        }
+    }
+
+    /// 送り先、同期、取り出す頻度を環境変数 `DATA_POST_URL`, `RAW_DATA_PATH`,
+    /// `STAGE_READY_DIO_LINE`, `STAGE_READY_TIMEOUT_MS`, `SCAN_DIO_LINE`,
+    /// `POLL_TARGET_BLOCK`, `POLL_MAX_LATENCY_MS`, `ABORT_ON_OVERFLOW` から読んだ設定
+    pub fn from_env() -> Self {
+        dotenv().ok();
+        let sync = SyncConfig::default();
+        RunConfig {
+            sinks: SinkConfig {
+                post_url: env::var("DATA_POST_URL").ok(),
+                raw_data_path: env::var("RAW_DATA_PATH").ok(),
+                ..SinkConfig::default()
+            },
+            sync: SyncConfig {
+                ready_line: read_env("STAGE_READY_DIO_LINE"),
+                ready_timeout: read_env("STAGE_READY_TIMEOUT_MS")
+                    .map_or(sync.ready_timeout, Duration::from_millis),
+                scan_line: read_env("SCAN_DIO_LINE"),
+            },
+            polling: PollPolicy::from_env(),
+            ..RunConfig::default()
+        }
+    }
+
+    /// 設定ファイルを読む。拡張子が `.json` ならJSON、それ以外はTOMLとして読む
+    /// 書かれていない値は `from_env` と同じになる
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Vec<String>> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| vec![format!("{}: {}", path.display(), e)])?;
+
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => RunConfig::from_json(&text),
+            _ => RunConfig::from_toml(&text),
+        }
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, Vec<String>> {
+        let file: ConfigFile = toml::from_str(text).map_err(|e| vec![e.to_string()])?;
+        file.into_config()
+    }
+
+    pub fn from_json(text: &str) -> Result<Self, Vec<String>> {
+        let file: ConfigFile = serde_json::from_str(text).map_err(|e| vec![e.to_string()])?;
+        file.into_config()
+    }
+
+    /// 計測を始める前に設定を確かめ、問題を全て返す
+    /// 装置のIDは装置を開くときに確かめる
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = vec![];
+        let mut check = |ok: bool, message: &str| {
+            if !ok {
+                errors.push(message.to_string());
+            }
+        };
+
+        if let Clock::Internal(clk_time) = self.clock {
+            check(
+                clk_time >= MIN_CLK_TIME,
+                "clock.clk_time: must be 500 or more",
+            );
+        }
+        if let Trigger::Analog {
+            ch,
+            level,
+            hysteresis,
+            ..
+        } = self.trigger
+        {
+            check(ch <= 1, "trigger.channel: must be 0 (CH1) or 1 (CH2)");
+            check(
+                (0..=660).contains(&hysteresis),
+                "trigger.hysteresis: must be 0 ~ 660",
+            );
+            let range = if ch == 0 {
+                self.ranges.0
+            } else {
+                self.ranges.1
+            };
+            let code = match level {
+                TriggerLevel::Code(code) => code,
+                TriggerLevel::Volts(volts) => range.to_code(volts),
+            };
+            check(
+                (1..=65534).contains(&code),
+                "trigger.level: must be inside the input range of the trigger channel",
+            );
+        }
+        check(self.prelen >= 0, "trigger.prelen: must not be negative");
+        match self.filter {
+            PositionFilter::SavitzkyGolay { window } => check(
+                SAVITZKY_GOLAY_WINDOWS.contains(&window),
+                "filter.window: must be 5, 7 or 9",
+            ),
+            PositionFilter::Lowpass { cutoff } => check(
+                cutoff > 0.0 && cutoff < self.clock.rate() / 2.0,
+                "filter.cutoff: must be between 0 and half the sampling rate",
+            ),
+            PositionFilter::None => {}
+        }
+
+        check(
+            self.sinks.post_url.is_some(),
+            "sinks.post_url: not set, and DATA_POST_URL is not set either",
+        );
+        check(
+            self.sinks.post_interval > Duration::ZERO,
+            "sinks.post_interval_ms: must be positive",
+        );
+
+        check(
+            self.sync.ready_line.unwrap_or(0) < 8,
+            "sync.ready_line: must be 0 ~ 7",
+        );
+        check(
+            self.sync.scan_line.unwrap_or(0) < 8,
+            "sync.scan_line: must be 0 ~ 7",
+        );
+
+        check(
+            self.stop != StopConditions::default(),
+            "stop: set at least one condition",
+        );
+        check(
+            self.stop.dio_line.unwrap_or(0) < 8,
+            "stop.dio_line: must be 0 ~ 7",
+        );
+        check(
+            self.channels == Channels::Both
+                || (self.stop.sweeps.is_none() && self.stop.bin_count.is_none()),
+            "stop: sweeps and bin_count need both channels",
+        );
+
+        check(
+            self.polling.target_block > 0,
+            "polling.target_block: must be positive",
+        );
+        check(
+            self.polling.max_latency > Duration::ZERO,
+            "polling.max_latency_ms: must be positive",
+        );
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+}
+
+/// 環境変数 `name` の値を読む。読めない値は表示して使わない
+fn read_env<T: FromStr>(name: &str) -> Option<T> {
+    let value = env::var(name).ok()?;
+    let parsed = value.trim().parse().ok();
+    if parsed.is_none() {
+        println!("Invalid {} '{}'", name, value);
+    }
+    parsed
+}
+
+/// 設定ファイルの内容。省略した値は `RunConfig::from_env` の値を使う
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    id: Option<c_short>,
+    /// `+/-10V` などの形で、CH1, CH2の順
+    ranges: Option<[String; 2]>,
+    /// `ch1`, `ch2`, `both`
+    channels: Option<String>,
+    clock: ClockSection,
+    trigger: TriggerSection,
+    filter: FilterSection,
+    binning: BinningSection,
+    sinks: SinkSection,
+    sync: SyncSection,
+    stop: StopSection,
+    polling: PollingSection,
+}
+
+/// `clk_time`, `rate`, `external_rate` のどれか1つを指定する
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ClockSection {
+    clk_time: Option<c_int>,
+    rate: Option<f64>,
+    external_rate: Option<f64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TriggerSection {
+    /// `software`, `external`, `rising`, `falling`
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    channel: Option<c_uchar>,
+    /// 基準レベル [V]
+    level: Option<f32>,
+    hysteresis: Option<c_short>,
+    prelen: Option<c_int>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FilterSection {
+    /// `savitzky_golay`, `lowpass`, `none`
+    position: Option<String>,
+    /// ローパスフィルタのカットオフ周波数 [Hz]
+    cutoff: Option<f64>,
+    /// Savitzky-Golayフィルタの点数
+    window: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct BinningSection {
+    capacity: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SinkSection {
+    post_url: Option<String>,
+    post_interval_ms: Option<u64>,
+    raw_data_path: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SyncSection {
+    ready_line: Option<u8>,
+    ready_timeout_ms: Option<u64>,
+    scan_line: Option<u8>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct StopSection {
+    seconds: Option<u64>,
+    samples: Option<u64>,
+    sweeps: Option<u64>,
+    bin_count: Option<u64>,
+    dio_line: Option<u8>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PollingSection {
+    target_block: Option<usize>,
+    max_latency_ms: Option<u64>,
+    abort_on_overflow: Option<bool>,
+}
+
+impl ConfigFile {
+    /// 値を変換し、変換できなかった値と `RunConfig::validate` の問題をまとめて返す
+    fn into_config(self) -> Result<RunConfig, Vec<String>> {
+        let mut errors = vec![];
+        let mut config = RunConfig::from_env();
+
+        if let Some(id) = self.id {
+            config.id = id;
+        }
+        if let Some([ch1, ch2]) = self.ranges {
+            match (ch1.parse(), ch2.parse()) {
+                (Ok(ch1), Ok(ch2)) => config.ranges = (ch1, ch2),
+                (ch1, ch2) => errors.extend(
+                    [ch1.err(), ch2.err()]
+                        .iter()
+                        .flatten()
+                        .map(|e| format!("ranges: {}", e)),
+                ),
+            }
+        }
+        if let Some(channels) = self.channels {
+            match channels.parse() {
+                Ok(channels) => config.channels = channels,
+                Err(e) => errors.push(format!("channels: {}", e)),
+            }
+        }
+
+        let clock = self.clock;
+        let clock = match (clock.clk_time, clock.rate, clock.external_rate) {
+            (None, None, None) => Ok(config.clock),
+            (Some(clk_time), None, None) => Ok(Clock::Internal(clk_time)),
+            (None, Some(rate), None) => Clock::from_rate(rate)
+                .map_err(|_| format!("clock.rate: {} Hz is out of 0 ~ 100 kHz", rate)),
+            (None, None, Some(rate)) => Clock::external(rate)
+                .map_err(|_| format!("clock.external_rate: {} Hz is not positive", rate)),
+            _ => Err("clock: set only one of clk_time, rate and external_rate".to_string()),
+        };
+        match clock {
+            Ok(clock) => config.clock = clock,
+            Err(e) => errors.push(e),
+        }
+
+        let trigger = self.trigger;
+        let trig_type = match trigger.kind.as_deref() {
+            None | Some("software") => Some(0),
+            Some("external") => Some(1),
+            Some("rising") => Some(2),
+            Some("falling") => Some(3),
+            Some(other) => {
+                errors.push(format!("trigger.type: Unknown trigger '{}'", other));
+                None
+            }
+        };
+        if let Some(trig_type) = trig_type {
+            // ソフトウェアトリガと外部トリガはチャネルもレベルも使わない
+            let analog_only = [
+                trigger.channel.is_some(),
+                trigger.level.is_some(),
+                trigger.hysteresis.is_some(),
+            ];
+            if trig_type < 2 && analog_only.contains(&true) {
+                errors.push(
+                    "trigger: channel, level and hysteresis are only for the rising and falling triggers"
+                        .to_string(),
+                );
+            }
+            let level = TriggerLevel::Volts(trigger.level.unwrap_or(0.0));
+            let trig_ch = trigger.channel.unwrap_or(0);
+            let hysteresis = trigger.hysteresis.unwrap_or(0);
+            // 種類は確かめてあるので失敗しない
+            if let Ok(trigger) = Trigger::from_raw(trig_type, trig_ch, level, hysteresis) {
+                config.trigger = trigger;
+            }
+        }
+        if let Some(prelen) = trigger.prelen {
+            config.prelen = prelen;
+        }
+
+        let filter = self.filter;
+        match (filter.position.as_deref(), filter.cutoff, filter.window) {
+            (None, None, None) => {}
+            (None, Some(_), _) | (Some("lowpass"), None, _) => {
+                errors.push("filter: cutoff is needed for the lowpass filter".to_string())
+            }
+            (Some("lowpass"), Some(cutoff), None) => {
+                config.filter = PositionFilter::Lowpass { cutoff }
+            }
+            (Some("lowpass"), Some(_), Some(_)) | (Some("none"), None, Some(_)) => {
+                errors.push("filter.window: only for the savitzky_golay filter".to_string())
+            }
+            (Some(_), Some(_), _) => {
+                errors.push("filter.cutoff: only for the lowpass filter".to_string())
+            }
+            (None, None, window @ Some(_)) | (Some("savitzky_golay"), None, window) => {
+                config.filter = PositionFilter::SavitzkyGolay {
+                    window: window.unwrap_or(SAVITZKY_GOLAY_WINDOW),
+                }
+            }
+            (Some("none"), None, None) => config.filter = PositionFilter::None,
+            (Some(other), None, _) => {
+                errors.push(format!("filter.position: Unknown filter '{}'", other))
+            }
+        }
+
+        if let Some(capacity) = self.binning.capacity {
+            config.capacity = capacity;
+        }
+
+        let sinks = self.sinks;
+        if sinks.post_url.is_some() {
+            config.sinks.post_url = sinks.post_url;
+        }
+        if let Some(interval) = sinks.post_interval_ms {
+            config.sinks.post_interval = Duration::from_millis(interval);
+        }
+        if sinks.raw_data_path.is_some() {
+            config.sinks.raw_data_path = sinks.raw_data_path;
+        }
+
+        let sync = self.sync;
+        if sync.ready_line.is_some() {
+            config.sync.ready_line = sync.ready_line;
+        }
+        if let Some(timeout) = sync.ready_timeout_ms {
+            config.sync.ready_timeout = Duration::from_millis(timeout);
+        }
+        if sync.scan_line.is_some() {
+            config.sync.scan_line = sync.scan_line;
+        }
+
+        let stop = self.stop;
+        config.stop = StopConditions {
+            duration: stop.seconds.map(Duration::from_secs),
+            samples: stop.samples,
+            sweeps: stop.sweeps,
+            bin_count: stop.bin_count,
+            dio_line: stop.dio_line,
+        };
+
+        if let Some(target_block) = self.polling.target_block {
+            config.polling.target_block = target_block;
+        }
+        if let Some(max_latency) = self.polling.max_latency_ms {
+            config.polling.max_latency = Duration::from_millis(max_latency);
+        }
+        if let Some(abort) = self.polling.abort_on_overflow {
+            config.polling.abort_on_overflow = abort;
+        }
+
+        if let Err(invalid) = config.validate() {
+            errors.extend(invalid);
+        }
+        match errors.is_empty() {
+            true => Ok(config),
+            false => Err(errors),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::operations::trigger::Edge;
+
+    #[test]
+    fn test_from_toml() {
+        let config = RunConfig::from_toml(
+            r#"
+            id = 1
+            ranges = ["+/-10V", "+/-2.5V"]
+            channels = "both"
+
+            [clock]
+            rate = 50000.0
+
+            [trigger]
+            type = "rising"
+            channel = 1
+            level = 0.5
+            hysteresis = 100
+            prelen = 1000
+
+            [filter]
+            position = "lowpass"
+            cutoff = 3000.0
+
+            [sinks]
+            post_url = "http://localhost:8000/core/rapid-scan-data/"
+            post_interval_ms = 500
+
+            [stop]
+            seconds = 10
+            sweeps = 500
+
+            [polling]
+            target_block = 4096
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.id, 1);
+        assert_eq!(
+            config.ranges,
+            (InputRange::Bipolar10V, InputRange::Bipolar2_5V)
+        );
+        assert_eq!(config.clock, Clock::Internal(1000));
+        assert_eq!(
+            config.trigger,
+            Trigger::Analog {
+                ch: 1,
+                edge: Edge::Rising,
+                level: TriggerLevel::Volts(0.5),
+                hysteresis: 100,
+            }
+        );
+        assert_eq!(config.prelen, 1000);
+        assert_eq!(config.filter, PositionFilter::Lowpass { cutoff: 3000.0 });
+        assert_eq!(config.sinks.post_interval, Duration::from_millis(500));
+        assert_eq!(config.stop.duration, Some(Duration::from_secs(10)));
+        assert_eq!(config.stop.sweeps, Some(500));
+        assert_eq!(config.polling.target_block, 4096);
+        assert_eq!(config.capacity, 20000);
+    }
+
+    #[test]
+    fn test_from_json() {
+        let config = RunConfig::from_json(
+            r#"{
+                "channels": "ch2",
+                "clock": {"external_rate": 20000.0},
+                "filter": {"window": 9},
+                "sinks": {"post_url": "http://localhost:8000/"},
+                "sync": {"ready_line": 2, "scan_line": 3},
+                "stop": {"samples": 2000000},
+                "polling": {"abort_on_overflow": true}
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.channels, Channels::Ch2);
+        assert_eq!(config.clock.rate(), 20000.0);
+        assert_eq!(config.stop.samples, Some(2000000));
+        assert_eq!(config.trigger, Trigger::Software);
+        assert_eq!(config.filter, PositionFilter::SavitzkyGolay { window: 9 });
+        assert_eq!(config.sync.ready_line, Some(2));
+        assert_eq!(config.sync.scan_line, Some(3));
+        assert!(config.polling.abort_on_overflow);
+    }
+
+    #[test]
+    fn test_all_errors() {
+        let errors = RunConfig::from_toml(
+            r#"
+            ranges = ["+/-10V", "3V"]
+            channels = "ch1"
+
+            [clock]
+            clk_time = 500
+            rate = 1000.0
+
+            [trigger]
+            type = "rising"
+            level = 12.0
+
+            [filter]
+            position = "median"
+
+            [stop]
+            sweeps = 10
+            dio_line = 9
+            "#,
+        )
+        .unwrap_err();
+
+        // 変換できない値と設定の問題を全て報告する
+        let expected = [
+            "ranges:",
+            "clock:",
+            "filter.position:",
+            "trigger.level:",
+            "stop.dio_line:",
+            "stop: sweeps and bin_count",
+        ];
+        for prefix in expected.iter() {
+            assert!(
+                errors.iter().any(|e| e.starts_with(prefix)),
+                "{} in {:?}",
+                prefix,
+                errors
+            );
+        }
+    }
+
+    #[test]
+    fn test_unused_values() {
+        let errors = RunConfig::from_toml(
+            r#"
+            [trigger]
+            type = "software"
+            level = 0.5
+
+            [filter]
+            window = 4
+
+            [sync]
+            scan_line = 8
+
+            [stop]
+            seconds = 1
+            "#,
+        )
+        .unwrap_err();
+
+        // ソフトウェアトリガのレベルは黙って捨てずに問題として報告する
+        let expected = ["trigger:", "filter.window:", "sync.scan_line:"];
+        for prefix in expected.iter() {
+            assert!(
+                errors.iter().any(|e| e.starts_with(prefix)),
+                "{} in {:?}",
+                prefix,
+                errors
+            );
+        }
+
+        let errors =
+            RunConfig::from_toml("[filter]\nposition = \"none\"\nwindow = 7\n").unwrap_err();
+        assert!(
+            errors.iter().any(|e| e.starts_with("filter.window:")),
+            "{:?}",
+            errors
+        );
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        let errors = RunConfig::from_toml("[stop]\nsecond = 10\n").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("second"), "{:?}", errors);
+    }
+}
@@ -1,23 +1,29 @@
-use crate::operations::interface;
+use super::config::RunConfig;
+use super::queue::{BlockQueue, BlockSender};
+use super::run::{Run, RunState, StopReason};
+use crate::operations::{
+    sampling_time, AcquisitionStatus, AdBackend, AdError, Channels, Device, DeviceStatus, Trigger,
+};
 use crate::RawDataset;
 use signalo_filters::convolve::savitzky_golay::SavitzkyGolay;
 use signalo_filters::convolve::*;
 use signalo_filters::signalo_traits::Filter;
 use std::cmp::min;
+use std::env;
 use std::fs::File;
-use std::io::Write;
-use std::os::raw::{c_int, c_short, c_uint};
+use std::io::{BufWriter, Write};
+use std::os::raw::{c_int, c_uchar, c_uint};
 use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 use std::{thread, time};
 use synthrs::filter::{convolve, cutoff_from_frequency, lowpass_filter};
 
 /// low pass filter
-/// cutoff: 30 Hz
-/// sampling rate: 100 kHz
-/// band: 0.01
-#[allow(dead_code)]
-fn lowpass(sample: &Vec<c_int>) -> Vec<c_int> {
-    let filter = lowpass_filter(cutoff_from_frequency(3000.0, 1000_000), 0.1);
+/// cutoff: `cutoff` Hz
+/// sampling rate: `sample_rate` Hz
+/// band: 0.1
+fn lowpass(sample: &Vec<c_int>, cutoff: f64, sample_rate: f64) -> Vec<c_int> {
+    let filter = lowpass_filter(cutoff_from_frequency(cutoff, sample_rate as usize), 0.1);
     let sample: Vec<f64> = sample.into_iter().map(|x| *x as f64).collect();
 
     convolve(&filter, sample.as_slice())
@@ -26,9 +32,26 @@ fn lowpass(sample: &Vec<c_int>) -> Vec<c_int> {
         .collect()
 }
 
-#[allow(dead_code)]
-fn savitzky_golay(sample: &Vec<c_int>) -> Vec<c_int> {
-    let filter: Convolve<f64, 5> = Convolve::savitzky_golay();
+/// Savitzky-Golayフィルタの点数の既定値
+pub const SAVITZKY_GOLAY_WINDOW: usize = 5;
+/// 使えるSavitzky-Golayフィルタの点数
+pub const SAVITZKY_GOLAY_WINDOWS: [usize; 3] = [5, 7, 9];
+
+/// `window` 点のSavitzky-Golayフィルタ
+/// 使えない点数は `RunConfig::validate` で弾くので、既定値の点数で掛ける
+fn savitzky_golay(sample: &Vec<c_int>, window: usize) -> Vec<c_int> {
+    match window {
+        7 => smooth::<7>(sample),
+        9 => smooth::<9>(sample),
+        _ => smooth::<SAVITZKY_GOLAY_WINDOW>(sample),
+    }
+}
+
+fn smooth<const N: usize>(sample: &Vec<c_int>) -> Vec<c_int>
+where
+    Convolve<f64, N>: SavitzkyGolay,
+{
+    let filter: Convolve<f64, N> = Convolve::savitzky_golay();
     sample
         .iter()
         .scan(filter, |filter, &input| Some(filter.filter(input as f64)))
@@ -36,6 +59,150 @@ fn savitzky_golay(sample: &Vec<c_int>) -> Vec<c_int> {
         .collect()
 }
 
+/// ステージの位置に掛けるフィルタ
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PositionFilter {
+    /// `window` 点のSavitzky-Golayフィルタ
+    SavitzkyGolay { window: usize },
+    /// カットオフ周波数 `cutoff` [Hz] のローパスフィルタ
+    Lowpass { cutoff: f64 },
+    /// フィルタを掛けない
+    None,
+}
+
+impl PositionFilter {
+    /// `block` のCH1にフィルタを掛ける
+    fn apply(&self, block: &Block) -> Vec<c_int> {
+        match *self {
+            PositionFilter::SavitzkyGolay { window } => savitzky_golay(&block.ch1, window),
+            PositionFilter::Lowpass { cutoff } => lowpass(&block.ch1, cutoff, block.sample_rate),
+            PositionFilter::None => block.ch1.clone(),
+        }
+    }
+}
+
+/// 装置から一度に取り出したCH1, CH2のデータ
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    /// ブロックの先頭のサンプルの、取り込み開始からの通し番号
+    pub offset: usize,
+    pub ch1: Vec<c_int>,
+    pub ch2: Vec<c_int>,
+    /// トリガが掛かったサンプルのブロック内での位置
+    /// トリガのサンプルを含まないブロックでは `None`
+    pub trigger: Option<usize>,
+    /// トリガが掛かったサンプルの通し番号
+    pub trigger_index: usize,
+    /// サンプリング周波数 [Hz]
+    pub sample_rate: f64,
+}
+
+impl Block {
+    /// # Arguments
+    ///
+    /// * offset - ブロックの先頭のサンプルの通し番号
+    /// * ch1, ch2 - 取り出したデータ
+    /// * trigger_index - トリガが掛かったサンプルの通し番号。装置に溜まっていたトリガ前のサンプル数に等しい
+    /// * sample_rate - サンプリング周波数 [Hz]
+    pub fn new(
+        offset: usize,
+        ch1: Vec<c_int>,
+        ch2: Vec<c_int>,
+        trigger_index: usize,
+        sample_rate: f64,
+    ) -> Self {
+        let length = ch1.len().max(ch2.len());
+        let trigger = if (offset..offset + length).contains(&trigger_index) {
+            Some(trigger_index - offset)
+        } else {
+            None
+        };
+
+        Block {
+            offset,
+            ch1,
+            ch2,
+            trigger,
+            trigger_index,
+            sample_rate,
+        }
+    }
+
+    /// サンプル数。1チャネルだけを取り込んだ場合はもう一方のチャネルは空になる
+    pub fn len(&self) -> usize {
+        self.ch1.len().max(self.ch2.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 先頭の `length` 個のサンプルだけを残す
+    pub fn truncate(&mut self, length: usize) {
+        self.ch1.truncate(length);
+        self.ch2.truncate(length);
+        self.trigger = self.trigger.filter(|trigger| *trigger < length);
+    }
+}
+
+/// ステージの折り返しを数え、端から端まで動いた回数を求める
+/// ノイズで折り返したと数えないように、位置が `SWEEP_HYSTERESIS` 以上戻った時点で折り返しとする
+#[derive(Debug, Default)]
+struct SweepCounter {
+    /// 今の向きで最も進んだ位置
+    extreme: Option<c_int>,
+    /// 位置が増える向きに動いているか。向きが決まるまでは `None`
+    rising: Option<bool>,
+    /// 折り返した回数
+    turns: u64,
+}
+
+/// +/-10Vのレンジで1 V
+const SWEEP_HYSTERESIS: c_int = 3277;
+
+impl SweepCounter {
+    fn update(&mut self, positions: &[c_int]) {
+        for &position in positions {
+            let extreme = *self.extreme.get_or_insert(position);
+            let (advanced, returned) = match self.rising {
+                Some(true) => (position > extreme, extreme - position),
+                Some(false) => (position < extreme, position - extreme),
+                // 最初の位置から大きく動いた向きを最初の向きとする
+                None => {
+                    if (position - extreme).abs() >= SWEEP_HYSTERESIS {
+                        self.rising = Some(position > extreme);
+                        self.extreme = Some(position);
+                    }
+                    continue;
+                }
+            };
+
+            if advanced {
+                self.extreme = Some(position);
+            } else if returned >= SWEEP_HYSTERESIS {
+                self.rising = self.rising.map(|rising| !rising);
+                self.extreme = Some(position);
+                self.turns += 1;
+            }
+        }
+    }
+
+    /// 最初の折り返しより前は途中から動いているので数えない
+    fn sweeps(&self) -> u64 {
+        self.turns.saturating_sub(1)
+    }
+}
+
+/// 半分以上の位置で平均されたサンプル数。位置ごとのサンプル数の中央値
+fn median_count(dataset: &[RawDataset]) -> u64 {
+    if dataset.is_empty() {
+        return 0;
+    }
+    let mut counts: Vec<u32> = dataset.iter().map(|data| data.len).collect();
+    let middle = counts.len() / 2;
+    *counts.select_nth_unstable(middle).1 as u64
+}
+
 // ステージのポジション(tmp1)ごとにデータをまとめる
 // +/-10Vとして位置測定をしていると仮定している
 fn update_data(
@@ -69,97 +236,776 @@ fn update_data(
     }
 }
 
-/// 装置の連続データ取り込みの制御。指定の時間だけデータ取り込みを行う
+/// 装置の連続データ取り込みの制御。トリガが掛かってから `run` の終了条件を満たすまでデータ取り込みを行う
 /// このメソッドではデータの取り込み開始、終了を制御するだけで装置のバッファに
 /// たまったデータの取り出しは行わない
+/// 他のスレッドでエラーが起きた場合や計測が中止された場合は条件を待たずに終了する
 ///
 /// # Arguments
 ///
-/// * id - 装置のユニット番号選択スイッチの数字
-/// * seconds - データ取り込みを行う秒数
-/// * flag - データ取り込み中であるかを判別するフラグ
-pub fn continuous_read(id: c_short, clk_time: c_int, seconds: u64, flag: Arc<Mutex<i8>>) {
-    let sleeping_time = time::Duration::from_secs(seconds);
+/// * device - 開いている装置
+/// * config - クロック、レンジ、トリガなどの計測の設定
+/// * run - 計測の状態
+pub fn continuous_read<B: AdBackend + ?Sized>(
+    device: &Device<B>,
+    config: &RunConfig,
+    run: &Run,
+) -> Result<(), AdError> {
+    run.advance(RunState::Arming);
+    let result = read_for(device, config, run);
 
-    // CH1, 2ともに+/-10Vの入力を受け付ける
-    // 入力が+/-10VなのはSR830の仕様
-    interface::input_set(id, 0, 0);
-    interface::set_clock(id, clk_time, 0);
-    interface::start(id, 2, 0, 0, 0);
-    interface::trigger(id);
+    // 他のスレッドに取り込みの終了を知らせる
+    match result {
+        Ok(()) => run.advance(RunState::Stopping),
+        Err(e) => run.fail(e),
+    }
+    println!("Timer stopped");
+    result
+}
 
-    *flag.lock().unwrap() = 0; // 計測開始のフラグを立てる
-    thread::sleep(sleeping_time);
+fn read_for<B: AdBackend + ?Sized>(
+    device: &Device<B>,
+    config: &RunConfig,
+    run: &Run,
+) -> Result<(), AdError> {
+    let sync = &config.sync;
 
-    interface::stop(id);
+    // ステージの準備ができたことをデジタル入力で受け取る場合はそれを待つ
+    if let Some(line) = sync.ready_line {
+        if !wait_for_dio_input(device, line, sync.ready_timeout, run)? {
+            return Ok(());
+        }
+    }
 
-    *flag.lock().unwrap() = 1; // 計測終了のフラグを立てる
-    println!("Timer stopped");
+    let (ch1_range, ch2_range) = config.ranges;
+    device.input_set(ch1_range, ch2_range)?;
+    let rate = device.configure_clock(&config.clock)?;
+    println!("Sampling rate: {} Hz", rate);
+
+    // 計測中であることをデジタル出力で外部に知らせる
+    if let Some(line) = sync.scan_line {
+        set_dio_output(device, line, true)?;
+    }
+
+    let result = sample_for(device, config, rate, run);
+
+    match sync.scan_line {
+        Some(line) => result.and(set_dio_output(device, line, false)),
+        None => result,
+    }
+}
+
+fn sample_for<B: AdBackend + ?Sized>(
+    device: &Device<B>,
+    config: &RunConfig,
+    rate: f64,
+    run: &Run,
+) -> Result<(), AdError> {
+    device.start_triggered(config.channels.code(), config.prelen, &config.trigger)?;
+
+    // ハードウェアトリガの場合は装置側でトリガが掛かるのを待つ
+    let mut result = match config.trigger {
+        Trigger::Software => wait_pretrigger(device, config.channels, config.prelen, rate, run)
+            .and_then(|_| device.trigger()),
+        _ => Ok(()),
+    };
+    if result.is_ok() {
+        run.advance(RunState::Running);
+        result = wait_for_stop(device, run);
+    }
+
+    // 止める前に状態を進め、取り込み側が異常な停止と区別できるようにする
+    run.advance(RunState::Stopping);
+    // トリガに失敗した場合も取り込みは止めておく
+    let stopped = device.stop();
+    result.and(stopped)
+}
+
+/// ソフトウェアトリガを掛ける前に、トリガ前のサンプルが `prelen` だけ溜まるのを待つ
+/// 装置が溜まった数を返さない場合も、溜まるはずの時間が過ぎれば待つのをやめる
+///
+/// # Arguments
+///
+/// * device - 連続取り込みを始めた装置
+/// * channels - 取り込むチャネル
+/// * prelen - トリガより前に取り込むサンプル数
+/// * rate - サンプリング周波数 [Hz]
+/// * run - 計測の状態
+fn wait_pretrigger<B: AdBackend + ?Sized>(
+    device: &Device<B>,
+    channels: Channels,
+    prelen: c_int,
+    rate: f64,
+    run: &Run,
+) -> Result<(), AdError> {
+    const CHECK_INTERVAL: time::Duration = time::Duration::from_millis(1);
+
+    if prelen <= 0 {
+        return Ok(());
+    }
+    // 溜まるはずの時間が表せないほど長い場合は、溜まるかトリガ待ちが終わるまで待つ
+    let deadline = sampling_time(prelen as u64, rate)
+        .and_then(|fill_time| fill_time.checked_add(CHECK_INTERVAL))
+        .and_then(|wait| Instant::now().checked_add(wait));
+    loop {
+        let device_status = device.status(false)?;
+        if device_status.status != AcquisitionStatus::WaitingForTrigger
+            || device_status.datalen(channels) >= prelen as c_uint
+            || matches!(deadline, Some(deadline) if Instant::now() >= deadline)
+        {
+            return Ok(());
+        }
+        if !run.sleep(CHECK_INTERVAL) {
+            return Ok(());
+        }
+    }
+}
+
+/// `run` の終了条件を満たすまで待ち、満たした条件を残す
+/// サンプル数の条件は取り込みスレッドが確認して止める
+fn wait_for_stop<B: AdBackend + ?Sized>(device: &Device<B>, run: &Run) -> Result<(), AdError> {
+    // 時間以外の条件を確認する間隔
+    const CHECK_INTERVAL: time::Duration = time::Duration::from_millis(10);
+
+    let conditions = run.conditions();
+    let started = Instant::now();
+    loop {
+        let elapsed = started.elapsed();
+        if let Some(reason) = conditions.reached(run, elapsed) {
+            run.stop(reason);
+            return Ok(());
+        }
+        if let Some(line) = conditions.dio_line {
+            if device.dio_read()? & (1 << line) != 0 {
+                run.stop(StopReason::DioInput);
+                return Ok(());
+            }
+        }
+
+        let interval = match conditions.duration {
+            Some(duration) => duration.saturating_sub(elapsed).min(CHECK_INTERVAL),
+            None => CHECK_INTERVAL,
+        };
+        if !run.sleep(interval) {
+            return Ok(());
+        }
+    }
+}
+
+/// デジタル出力の `line` ビット目だけを切り替える
+fn set_dio_output<B: AdBackend + ?Sized>(
+    device: &Device<B>,
+    line: u8,
+    high: bool,
+) -> Result<(), AdError> {
+    let output = device.dio_check()?;
+    let mask = 1 << line;
+
+    match high {
+        true => device.dio_write(output | mask),
+        false => device.dio_write(output & !mask),
+    }
 }
 
-fn cleanup_buffer(id: c_short) {
+/// デジタル入力の `line` ビット目がHighになるまで最大 `timeout` 待つ
+/// 待っている間に計測を終えるべき状態になった場合は `false` を返す
+fn wait_for_dio_input<B: AdBackend + ?Sized>(
+    device: &Device<B>,
+    line: u8,
+    timeout: Duration,
+    run: &Run,
+) -> Result<bool, AdError> {
+    let mask = 1 << line;
+    // 長すぎる時間は待ち続けることとして扱う
+    let deadline = Instant::now().checked_add(timeout);
+
+    while device.dio_read()? & mask == 0 {
+        if run.should_stop() {
+            return Ok(false);
+        }
+        if matches!(deadline, Some(deadline) if Instant::now() >= deadline) {
+            return Err(AdError::StageNotReady("STAGE_READY_DIO_LINE"));
+        }
+        thread::sleep(time::Duration::from_millis(1));
+    }
+    Ok(true)
+}
+
+fn cleanup_buffer<B: AdBackend + ?Sized>(device: &Device<B>) -> Result<(), AdError> {
     const MAX_LENGTH: usize = 262142;
     let mut data1: Vec<c_int> = vec![0; MAX_LENGTH];
     let mut data2: Vec<c_int> = vec![0; MAX_LENGTH];
 
-    let device_status = interface::status(false);
+    let device_status = device.status(false)?;
+
+    if device_status.status == AcquisitionStatus::Converting {
+        let length = min(device_status.ch1_datalen, device_status.ch2_datalen);
+        let length = min(length as usize, MAX_LENGTH);
+        let length = device.takeout_data(0, &mut data1[..length])?;
+        device.takeout_data(1, &mut data2[..length as usize])?;
+    }
+    Ok(())
+}
+
+/// 取り込みループの状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// 連続取り込みの開始前
+    Idle,
+    /// トリガ待ち
+    Armed,
+    /// トリガ後の変換中
+    Converting,
+}
+
+impl Phase {
+    /// 装置の状態から次の状態を決める
+    /// 開始後に装置が止まっていれば `DeviceStopped` を返す
+    fn next(self, status: AcquisitionStatus) -> Result<Self, AdError> {
+        match (self, status) {
+            (_, AcquisitionStatus::WaitingForTrigger) => Ok(Phase::Armed),
+            (_, AcquisitionStatus::Converting) => Ok(Phase::Converting),
+            (Phase::Idle, AcquisitionStatus::Stopped) => Ok(Phase::Idle),
+            (_, AcquisitionStatus::Stopped) => Err(AdError::DeviceStopped("TUSB0216AD_Ad_Status")),
+        }
+    }
+}
+
+/// 新しく起きたオーバーフローの数を数える
+/// オーバーフローの状態はクリアされるまで続くので、各チャネルで立ち上がった回数を数える
+///
+/// # Arguments
+///
+/// * previous - 前回確認したときのCH1, CH2のオーバーフローの状態
+/// * device_status - 今回確認した装置の状態
+fn count_overflows(previous: &mut [bool; 2], device_status: &DeviceStatus) -> u32 {
+    let count = previous
+        .iter()
+        .zip(device_status.overflow.iter())
+        .filter(|(before, now)| !**before && **now)
+        .count();
+    *previous = device_status.overflow;
+    count as u32
+}
+
+/// 装置のバッファを確認する最短の間隔
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// 装置のバッファからデータを取り出す頻度の決め方
+/// 目安のサンプル数が溜まるか、前回取り出してから最大の待ち時間が過ぎたら取り出す
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PollPolicy {
+    /// 一度に取り出すサンプル数の目安
+    pub target_block: usize,
+    /// 取り出す間隔の上限
+    pub max_latency: Duration,
+    /// オーバーフローが起きたら計測を中止するか
+    pub abort_on_overflow: bool,
+}
+
+impl Default for PollPolicy {
+    fn default() -> Self {
+        PollPolicy {
+            target_block: 8192,
+            max_latency: Duration::from_millis(100),
+            abort_on_overflow: false,
+        }
+    }
+}
+
+impl PollPolicy {
+    /// 環境変数 `POLL_TARGET_BLOCK` (サンプル数), `POLL_MAX_LATENCY_MS` (ミリ秒),
+    /// `ABORT_ON_OVERFLOW` (`1` または `true` なら中止する) から読む
+    /// 設定されていない値は既定値を使う
+    pub fn from_env() -> Self {
+        let default = PollPolicy::default();
+        let read = |name: &str| env::var(name).ok()?.parse::<u64>().ok();
+
+        PollPolicy {
+            target_block: read("POLL_TARGET_BLOCK").map_or(default.target_block, |n| n as usize),
+            max_latency: read("POLL_MAX_LATENCY_MS")
+                .map_or(default.max_latency, Duration::from_millis),
+            abort_on_overflow: matches!(
+                env::var("ABORT_ON_OVERFLOW").as_deref(),
+                Ok("1") | Ok("true")
+            ),
+        }
+    }
+
+    /// バッファがあふれないように制限する
+    /// バッファの半分が埋まる前に必ず取り出すようにする
+    ///
+    /// # Arguments
+    ///
+    /// * sample_rate - サンプリング周波数 [Hz]
+    /// * capacity - 1チャネルあたりのバッファのサンプル数
+    fn limited(self, sample_rate: f64, capacity: usize) -> Self {
+        let half = capacity / 2;
+        let fill_time = Duration::from_secs_f64(half as f64 / sample_rate);
+
+        PollPolicy {
+            target_block: self.target_block.clamp(1, half),
+            max_latency: self.max_latency.min(fill_time).max(MIN_POLL_INTERVAL),
+            ..self
+        }
+    }
+
+    /// 取り出すまでに待つ時間。今すぐ取り出す場合は `None` を返す
+    ///
+    /// # Arguments
+    ///
+    /// * available - バッファに溜まっているサンプル数
+    /// * since_read - 前回取り出してからの時間
+    /// * sample_rate - サンプリング周波数 [Hz]
+    fn wait(&self, available: usize, since_read: Duration, sample_rate: f64) -> Option<Duration> {
+        if available >= self.target_block || since_read >= self.max_latency {
+            return None;
+        }
+        let remaining = (self.target_block - available) as f64 / sample_rate;
+        let wait = Duration::from_secs_f64(remaining).min(self.max_latency - since_read);
+        Some(wait.max(MIN_POLL_INTERVAL))
+    }
+}
+
+/// `TUSB0216AD_Ad_Data` で一度に取り出せる最大のサンプル数
+const MAX_TAKEOUT: usize = 262144;
+
+/// チャネル `ch` のデータを `data` がいっぱいになるまで取り出す
+/// 1回の呼び出しで取り出せる数を超える場合は分けて取り出す
+///
+/// # Returns
+///
+/// 実際に取り出したサンプル数
+fn takeout_all<B: AdBackend + ?Sized>(
+    device: &Device<B>,
+    ch: c_uchar,
+    data: &mut [c_int],
+) -> Result<usize, AdError> {
+    let mut taken = 0;
+    for chunk in data.chunks_mut(MAX_TAKEOUT) {
+        let length = device.takeout_data(ch, chunk)? as usize;
+        taken += length;
+        if length < chunk.len() {
+            break;
+        }
+    }
+    Ok(taken)
+}
+
+/// 装置のバッファにたまったデータを1ブロックずつ取り出す
+struct BlockReader {
+    channels: Channels,
+    data1: Vec<c_int>,
+    data2: Vec<c_int>,
+    /// これまでに取り出したサンプル数
+    offset: usize,
+    /// トリガより前に取り込むサンプル数
+    prelen: usize,
+    /// トリガ待ちの間に装置に溜まっていたトリガ前のサンプル数
+    held: usize,
+    /// トリガが掛かったサンプルの通し番号。トリガ後に初めて取り出すまでは `None`
+    trigger_index: Option<usize>,
+    /// サンプリング周波数 [Hz]
+    sample_rate: f64,
+}
+
+impl BlockReader {
+    /// 1チャネルだけを取り込む場合は装置のメモリを1チャネルで使えるので
+    /// 1ブロックに2倍のサンプルを取り出す
+    fn new(channels: Channels, prelen: usize, sample_rate: f64) -> Self {
+        const MAX_LENGTH: usize = 262142;
+        let (length1, length2) = match channels {
+            Channels::Ch1 => (2 * MAX_LENGTH, 0),
+            Channels::Ch2 => (0, 2 * MAX_LENGTH),
+            Channels::Both => (MAX_LENGTH, MAX_LENGTH),
+        };
+
+        BlockReader {
+            channels,
+            data1: vec![0; length1],
+            data2: vec![0; length2],
+            offset: 0,
+            prelen,
+            held: 0,
+            trigger_index: None,
+            sample_rate,
+        }
+    }
+
+    /// 1ブロックに取り出せる最大のサンプル数
+    fn capacity(&self) -> usize {
+        self.data1.len().max(self.data2.len())
+    }
+
+    /// 装置のバッファに溜まっていて、次のブロックで取り出すサンプル数
+    fn available(&self, device_status: &DeviceStatus) -> usize {
+        min(
+            device_status.datalen(self.channels) as usize,
+            self.capacity(),
+        )
+    }
+
+    /// トリガ待ちの間に装置に溜まったトリガ前のサンプル数を記録する
+    /// 溜まりきる前にトリガが掛かると、トリガ前のサンプルは `prelen` より少なくなる
+    fn hold(&mut self, device_status: &DeviceStatus) {
+        if device_status.status == AcquisitionStatus::WaitingForTrigger {
+            self.held = self
+                .held
+                .max(self.available(device_status))
+                .min(self.prelen);
+        }
+    }
+
+    /// トリガ前のサンプルが `prelen` だけ溜まっているか
+    fn is_filled(&self) -> bool {
+        self.held >= self.prelen
+    }
 
-    if device_status.status == 3 {
-        let mut length = min(device_status.ch1_datalen, device_status.ch2_datalen);
-        let l_ptr = &mut length as *mut u32;
-        interface::takeout_data(id, 0, data1.as_mut_ptr(), l_ptr);
-        interface::takeout_data(id, 1, data2.as_mut_ptr(), l_ptr);
+    /// トリガ後の変換中でなければ `None` を返す
+    ///
+    /// # Arguments
+    ///
+    /// * device - 開いている装置
+    /// * device_status - 直前に確認した装置の状態
+    fn read<B: AdBackend + ?Sized>(
+        &mut self,
+        device: &Device<B>,
+        device_status: &DeviceStatus,
+    ) -> Result<Option<Block>, AdError> {
+        if device_status.status != AcquisitionStatus::Converting {
+            return Ok(None);
+        }
+        self.take(device, device_status).map(Some)
+    }
+
+    /// 装置の状態によらず、溜まっているサンプルを取り出す
+    /// トリガ後に装置を止めてから、残りを取り出すのに使う
+    ///
+    /// # Arguments
+    ///
+    /// * device - 開いている装置
+    /// * device_status - 直前に確認した装置の状態
+    fn take<B: AdBackend + ?Sized>(
+        &mut self,
+        device: &Device<B>,
+        device_status: &DeviceStatus,
+    ) -> Result<Block, AdError> {
+        // 最初に取り出すのはトリガ待ちの間に溜まっていたサンプルから
+        let trigger_index = *self.trigger_index.get_or_insert(self.held);
+
+        let length = self.available(device_status);
+        let (length1, length2) = match self.channels {
+            Channels::Ch1 => (takeout_all(device, 0, &mut self.data1[..length])?, 0),
+            Channels::Ch2 => (0, takeout_all(device, 1, &mut self.data2[..length])?),
+            Channels::Both => {
+                let length = takeout_all(device, 0, &mut self.data1[..length])?;
+                let length = takeout_all(device, 1, &mut self.data2[..length])?;
+                (length, length)
+            }
+        };
+
+        let block = Block::new(
+            self.offset,
+            self.data1[..length1].to_vec(),
+            self.data2[..length2].to_vec(),
+            trigger_index,
+            self.sample_rate,
+        );
+        self.offset += block.len();
+        Ok(block)
     }
 }
 
+/// 1チャネルだけを取り込んだブロックを時系列として追加する
+/// `x` はトリガからのサンプル数、`y` は取り込んだ値
+///
+/// # Arguments
+///
+/// * block - 取り出したブロック
+/// * dataset - 時系列を収納するベクトル
+fn append_series(block: &Block, dataset: &mut Vec<RawDataset>) {
+    let data = if block.ch1.is_empty() {
+        &block.ch2
+    } else {
+        &block.ch1
+    };
+    let start = block.offset as i64 - block.trigger_index as i64;
+
+    dataset.extend(data.iter().enumerate().map(|(i, y)| RawDataset {
+        x: (start + i as i64) as i32,
+        y: *y,
+        len: 1,
+    }));
+}
+
 /// データの取り込みが行われているフラグが立っている間
-/// CH1, CH2 からのデータを取得する
+/// CH1, CH2 からのデータを取得し、ブロックごとに `sender` のキューに渡す
+/// プレトリガのデータもトリガ後のデータと同じ順序で渡される
+/// 装置に実際に溜まっていたトリガ前のサンプル数を `run` に残す
+/// 装置がエラーを返した場合は終了フラグを立てて計測全体を止める
+/// バッファのオーバーフローは `run` に数え、`policy` で指定されていれば計測を止める
+/// 取り込むサンプル数が決まっている場合はちょうどその数で打ち切り、計測を止める
+/// 装置のバッファは `policy` に従って、ある程度溜まってからまとめて取り出す
+/// 止める時には装置が止まるのを待ち、残っているサンプルを取り出してから終える
+/// 終了すると `sender` のキューは閉じられる
 ///
 /// # Arguments
 ///
-/// * id - 装置のユニット番号選択スイッチの数字
-/// * flag - データ取り込み中であるかを判別するフラグ
-/// * position - CH1のデータを収納するベクトル
-/// * intensity - CH2のデータを収納するベクトル
-pub fn get_data(id: c_short, flag: Arc<Mutex<i8>>, dataset: Arc<Mutex<Vec<RawDataset>>>) {
-    const MAX_LENGTH: usize = 262142;
-    let mut length: c_uint;
+/// * device - 開いている装置
+/// * channels - 取り込むチャネル
+/// * prelen - トリガより前に取り込むサンプル数
+/// * sample_rate - サンプリング周波数 [Hz]
+/// * run - 計測の状態
+/// * sender - 取り出したブロックを処理するスレッドへのキュー
+/// * policy - バッファからデータを取り出す頻度
+pub fn get_data<B: AdBackend + ?Sized>(
+    device: &Device<B>,
+    channels: Channels,
+    prelen: usize,
+    sample_rate: f64,
+    run: &Run,
+    sender: BlockSender,
+    policy: PollPolicy,
+) -> Result<(), AdError> {
+    let mut reader = BlockReader::new(channels, prelen, sample_rate);
+    let result = acquire(device, &mut reader, run, &sender, policy);
+
+    if let Err(e) = result {
+        run.fail(e);
+    }
+    println!("Data acquisition stopped");
+    result
+}
 
-    cleanup_buffer(id);
+fn acquire<B: AdBackend + ?Sized>(
+    device: &Device<B>,
+    reader: &mut BlockReader,
+    run: &Run,
+    sender: &BlockSender,
+    policy: PollPolicy,
+) -> Result<(), AdError> {
+    cleanup_buffer(device)?;
 
     println!("Data acquisition started");
+    // 連続取り込みが始まらずに終わった場合は何もしない
+    if !run.wait_running() {
+        return Ok(());
+    }
+
+    let mut overflow = [false, false];
+    let abort = policy.abort_on_overflow;
+    let mut phase = Phase::Idle;
+    let policy = policy.limited(reader.sample_rate, reader.capacity());
+    let mut backoff = MIN_POLL_INTERVAL;
+    let mut last_read = Instant::now();
+
     loop {
-        if *flag.lock().unwrap() != -1 {
+        if run.should_stop() {
             break;
         }
-        thread::sleep(time::Duration::from_millis(1));
+
+        let device_status = device.status(false)?;
+        let next = match phase.next(device_status.status) {
+            Ok(next) => next,
+            // 計測時間が過ぎて止められた場合は状態が先に進んでいる
+            Err(_) if run.should_stop() => break,
+            Err(e) => return Err(e),
+        };
+        if next == Phase::Armed && phase != Phase::Armed {
+            println!("Armed, waiting for trigger");
+        }
+        if next == Phase::Converting && phase != Phase::Converting {
+            last_read = Instant::now();
+        }
+        phase = next;
+        reader.hold(&device_status);
+
+        let count = count_overflows(&mut overflow, &device_status);
+        if count > 0 {
+            run.add_overflows(count);
+            println!("Buffer overflowed: {:?}", device_status.overflow);
+            if abort {
+                return Err(AdError::BufferOverflow("TUSB0216AD_Ad_Status"));
+            }
+        }
+
+        if phase != Phase::Converting {
+            // トリガを待っている間は確認の間隔を延ばしていく
+            if !run.sleep(backoff) {
+                break;
+            }
+            // トリガ前のサンプルが溜まっている間は、溜まった数が分かるように間隔を延ばさない
+            if reader.is_filled() {
+                backoff = (backoff * 2).min(policy.max_latency);
+            }
+            continue;
+        }
+        backoff = MIN_POLL_INTERVAL;
+
+        let available = reader.available(&device_status);
+        if let Some(wait) = policy.wait(available, last_read.elapsed(), reader.sample_rate) {
+            if !run.sleep(wait) {
+                break;
+            }
+            continue;
+        }
+
+        last_read = Instant::now();
+        let block = match reader.read(device, &device_status)? {
+            Some(block) if !block.is_empty() => block,
+            _ => continue,
+        };
+        if deliver(block, run, sender) {
+            run.stop(StopReason::Samples);
+            return Ok(());
+        }
     }
 
-    let mut data1: Vec<c_int> = vec![0; MAX_LENGTH];
-    let mut data2: Vec<c_int> = vec![0; MAX_LENGTH];
+    // トリガ前に止めた場合は渡すデータがない
+    if phase == Phase::Converting {
+        drain(device, reader, run, sender)?;
+    }
+    Ok(())
+}
+
+/// 止めた装置に残っているサンプルを全て取り出して `sender` に渡す
+/// 止まるまでの間に変換されたサンプルも取りこぼさないように、装置が止まるのを待ってから取り出す
+fn drain<B: AdBackend + ?Sized>(
+    device: &Device<B>,
+    reader: &mut BlockReader,
+    run: &Run,
+    sender: &BlockSender,
+) -> Result<(), AdError> {
+    // 止まらないまま変換中を返し続ける装置は、この時間だけ待ってから残りを取り出す
+    const STOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+    let deadline = Instant::now() + STOP_TIMEOUT;
+    while device.status(false)?.status != AcquisitionStatus::Stopped && Instant::now() < deadline {
+        thread::sleep(MIN_POLL_INTERVAL);
+    }
 
     loop {
-        if *flag.lock().unwrap() == 1 {
-            break;
+        let device_status = device.status(false)?;
+        if reader.available(&device_status) == 0 {
+            return Ok(());
+        }
+        let block = reader.take(device, &device_status)?;
+        if block.is_empty() || deliver(block, run, sender) {
+            return Ok(());
         }
-        let device_status = interface::status(false);
+    }
+}
 
-        if device_status.status == 3 {
-            length = min(device_status.ch1_datalen, device_status.ch2_datalen);
-            let l_ptr = &mut length as *mut u32;
-            interface::takeout_data(id, 0, data1.as_mut_ptr(), l_ptr);
-            interface::takeout_data(id, 1, data2.as_mut_ptr(), l_ptr);
-        } else {
-            continue;
+/// `block` を取り込むサンプル数で切り詰めて `sender` に渡す
+/// 取り込むサンプル数に達したら `true` を返す
+fn deliver(mut block: Block, run: &Run, sender: &BlockSender) -> bool {
+    run.set_pretrigger(block.trigger_index);
+    let limit = run.conditions().samples;
+    if let Some(limit) = limit {
+        block.truncate(limit.saturating_sub(run.samples()) as usize);
+    }
+    run.add_samples(block.len());
+    sender.send(block, run);
+
+    matches!(limit, Some(limit) if run.samples() >= limit)
+}
+
+/// `queue` のブロックを `dataset` にまとめる。取り込みが終わってキューが空になるまで続ける
+/// 両チャネルの場合はステージの位置ごとにCH2を平均し、1チャネルの場合は時系列として追加する
+/// 両チャネルの場合はステージが端から端まで動いた回数と、位置ごとのサンプル数も `run` に反映する
+///
+/// # Arguments
+///
+/// * queue - 取り込みスレッドからのキュー
+/// * channels - 取り込むチャネル
+/// * filter - ステージの位置に掛けるフィルタ
+/// * run - 計測の状態
+/// * dataset - 両チャネルの場合はCH1の値ごとにCH2の平均を、1チャネルの場合は時系列を収納するベクトル
+pub fn bin_blocks(
+    queue: &BlockQueue,
+    channels: Channels,
+    filter: PositionFilter,
+    run: &Run,
+    dataset: Arc<Mutex<Vec<RawDataset>>>,
+) {
+    let mut sweeps = SweepCounter::default();
+    let count_bins = run.conditions().bin_count.is_some();
+
+    queue.consume(|block| {
+        if channels != Channels::Both {
+            append_series(block, &mut dataset.lock().unwrap());
+            return;
         }
 
-        // let position_denoised: Vec<c_int> = lowpass(&data1);
-        let position_denoised: Vec<c_int> = savitzky_golay(&data1);
+        // フィルタの立ち上がりで折り返したと数えないように、生の位置で数える
+        sweeps.update(&block.ch1);
+        run.set_sweeps(sweeps.sweeps());
+
+        // フィルタは `dataset` をロックする前に掛けておく
+        let position_denoised: Vec<c_int> = filter.apply(block);
 
         let mut dataset = dataset.lock().unwrap();
-        update_data(&position_denoised, &data2, &mut dataset, length);
+        update_data(
+            &position_denoised,
+            &block.ch2,
+            &mut dataset,
+            block.len() as c_uint,
+        );
+        run.set_bins(dataset.len());
+        if count_bins {
+            run.set_bin_count(median_count(&dataset));
+        }
+    });
+    println!("Binning stopped");
+}
+
+/// `queue` のブロックを加工せずに `path` にCSVで書き出す
+/// 各行はトリガからのサンプル数、CH1, CH2の値で、取り込んでいないチャネルは空になる
+/// 書き込みに失敗した場合もキューが溢れないように取り出しは続ける
+///
+/// # Arguments
+///
+/// * queue - 取り込みスレッドからのキュー
+/// * path - 書き出すファイル
+pub fn record_blocks(queue: &BlockQueue, path: &str) {
+    let mut file = match File::create(path) {
+        Ok(file) => Some(BufWriter::new(file)),
+        Err(e) => {
+            println!("Failed to create {}: {}", path, e);
+            None
+        }
+    };
+
+    queue.consume(|block| {
+        let result = match file.as_mut() {
+            Some(file) => write_block(file, block),
+            None => return,
+        };
+        if let Err(e) = result {
+            println!("Failed to write {}: {}", path, e);
+            file = None;
+        }
+    });
+    if let Some(mut file) = file {
+        file.flush().ok();
     }
-    println!("Data acquisition stopped");
+    println!("Recording stopped");
+}
+
+fn write_block<W: Write>(file: &mut W, block: &Block) -> std::io::Result<()> {
+    let start = block.offset as i64 - block.trigger_index as i64;
+    let value = |data: &[c_int], i: usize| data.get(i).map(|v| v.to_string());
+
+    for i in 0..block.len() {
+        writeln!(
+            file,
+            "{},{},{}",
+            start + i as i64,
+            value(&block.ch1, i).unwrap_or_default(),
+            value(&block.ch2, i).unwrap_or_default()
+        )?;
+    }
+    Ok(())
 }
 
 pub fn write_to_csv(file_name: &str, x: &Vec<f32>, y: &Vec<f32>) {
@@ -175,6 +1021,12 @@ pub fn write_to_csv(file_name: &str, x: &Vec<f32>, y: &Vec<f32>) {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::helpers::queue::QUEUE_CAPACITY;
+    use crate::helpers::run::StopConditions;
+    use crate::operations::fault::Fault;
+    use crate::operations::{
+        Clock, InputRange, RecordingBackend, ReplayBackend, Signal, SimulatedBackend, ThzPulse,
+    };
     use nearly_eq::*;
     use rand::Rng;
     use std::f64::consts::PI;
@@ -206,7 +1058,7 @@ mod test {
             y_noise[i] = y[i] + (2000.0 * 5e-3 * (noise - 0.5)) as i32;
         }
 
-        let denoised = lowpass(&y_noise);
+        let denoised = lowpass(&y_noise, 3000.0, 100e3);
         println!("{}", denoised.len());
 
         for i in 5..(DATA_NUM - 5) {
@@ -244,9 +1096,632 @@ mod test {
     fn test_continuous_read() {
         let seconds = 1;
         let start = Instant::now();
-        continuous_read(1, 500, seconds, Arc::new(Mutex::new(0)));
+        let device = Device::open(Arc::new(SimulatedBackend::new()), 0).unwrap();
+        let run = Run::with_conditions(StopConditions::duration(seconds));
+        continuous_read(&device, &RunConfig::default(), &run).unwrap();
         let end = start.elapsed();
 
         assert_nearly_eq!(end.as_millis() as f32, (seconds * 1000) as f32, 50.0);
+        assert_eq!(run.stop_reason(), Some(StopReason::Duration));
+    }
+
+    #[test]
+    fn test_continuous_read_error() {
+        let run = Run::with_conditions(StopConditions::duration(1));
+        let start = Instant::now();
+        let device = Device::open(Arc::new(SimulatedBackend::new()), 0).unwrap();
+        let result = continuous_read(
+            &device,
+            &RunConfig {
+                clock: Clock::Internal(499),
+                ..RunConfig::default()
+            },
+            &run,
+        );
+
+        assert!(result.is_err());
+        assert!(start.elapsed().as_millis() < 100);
+        assert_eq!(run.state(), RunState::Failed(result.unwrap_err()));
+    }
+
+    #[test]
+    fn test_continuous_read_usb_error() {
+        let backend = SimulatedBackend::new();
+        backend.inject(0, Fault::UsbError, time::Duration::from_millis(200));
+        let device = Device::open(Arc::new(backend), 0).unwrap();
+        let result = continuous_read(
+            &device,
+            &RunConfig::default(),
+            &Run::with_conditions(StopConditions::duration(1)),
+        );
+
+        assert_eq!(result, Err(AdError::UsbError("TUSB0216AD_Stop")));
+    }
+
+    #[test]
+    fn test_continuous_read_cancel() {
+        let device = Device::open(Arc::new(SimulatedBackend::new()), 0).unwrap();
+        let run = Arc::new(Run::with_conditions(StopConditions::duration(10)));
+        let canceller = {
+            let run = Arc::clone(&run);
+            thread::spawn(move || {
+                thread::sleep(time::Duration::from_millis(100));
+                run.cancel();
+            })
+        };
+
+        // 中止されると計測時間を待たずに正常に終わる
+        let start = Instant::now();
+        continuous_read(&device, &RunConfig::default(), &run).unwrap();
+        canceller.join().unwrap();
+
+        assert!(start.elapsed().as_millis() < 1000);
+        assert_eq!(run.state(), RunState::Stopping);
+        assert_eq!(
+            device.status(false).unwrap().status,
+            AcquisitionStatus::Stopped
+        );
+    }
+
+    /// 連続取り込み、データの取り出し、まとめをそれぞれのスレッドで `run` の終了条件まで行う
+    fn run_until(backend: SimulatedBackend, run: &Arc<Run>) -> (Vec<usize>, Vec<RawDataset>) {
+        let device = Arc::new(Device::open(Arc::new(backend), 0).unwrap());
+        let queues = [0, 1].map(|_| Arc::new(BlockQueue::new(QUEUE_CAPACITY)));
+        let dataset = Arc::new(Mutex::new(vec![]));
+        let binner = {
+            let (queue, run, dataset) = (
+                Arc::clone(&queues[0]),
+                Arc::clone(run),
+                Arc::clone(&dataset),
+            );
+            thread::spawn(move || {
+                bin_blocks(
+                    &queue,
+                    Channels::Both,
+                    PositionFilter::SavitzkyGolay {
+                        window: SAVITZKY_GOLAY_WINDOW,
+                    },
+                    &run,
+                    dataset,
+                )
+            })
+        };
+        let reader = {
+            let (device, run) = (Arc::clone(&device), Arc::clone(run));
+            let sender = BlockSender::new(queues.to_vec());
+            let policy = PollPolicy::default();
+            thread::spawn(move || get_data(&device, Channels::Both, 0, 100e3, &run, sender, policy))
+        };
+
+        continuous_read(&device, &RunConfig::default(), run).unwrap();
+        reader.join().unwrap().unwrap();
+        binner.join().unwrap();
+
+        let mut lengths = vec![];
+        queues[1].consume(|block| lengths.push(block.len()));
+        let dataset = dataset.lock().unwrap();
+        (lengths, dataset.clone())
+    }
+
+    #[test]
+    fn test_stop_by_samples() {
+        let run = Arc::new(Run::with_conditions(StopConditions {
+            samples: Some(50000),
+            duration: Some(time::Duration::from_secs(10)),
+            ..StopConditions::default()
+        }));
+        let (lengths, _) = run_until(SimulatedBackend::new(), &run);
+
+        // ちょうど指定した数で打ち切る
+        assert_eq!(lengths.iter().sum::<usize>(), 50000);
+        assert_eq!(run.samples(), 50000);
+        assert_eq!(run.stop_reason(), Some(StopReason::Samples));
+    }
+
+    #[test]
+    fn test_stop_by_sweeps() {
+        // 5 Hz の往復で、1秒に10回端から端まで動く
+        let stage = Signal {
+            frequency: 5.0,
+            ..Signal::default()
+        };
+        let backend = SimulatedBackend::new().with_signal(0, stage);
+        let run = Arc::new(Run::with_conditions(StopConditions {
+            sweeps: Some(3),
+            bin_count: Some(1_000_000),
+            duration: Some(time::Duration::from_secs(10)),
+            ..StopConditions::default()
+        }));
+        let start = Instant::now();
+        let (_, dataset) = run_until(backend, &run);
+
+        assert_eq!(run.stop_reason(), Some(StopReason::Sweeps));
+        assert!(run.sweeps() >= 3);
+        // 途中から始まる分と3回分で、0.5秒前後
+        let elapsed = start.elapsed().as_millis();
+        assert!((300..800).contains(&elapsed), "{}", elapsed);
+        assert!(run.bin_count() > 0);
+        assert_eq!(run.bin_count(), median_count(&dataset));
+    }
+
+    #[test]
+    fn test_stop_by_dio_input() {
+        let backend = Arc::new(SimulatedBackend::new());
+        let device = Device::open(Arc::clone(&backend), 0).unwrap();
+        let run = Arc::new(Run::with_conditions(StopConditions {
+            dio_line: Some(2),
+            ..StopConditions::default()
+        }));
+        let trigger = {
+            let backend = Arc::clone(&backend);
+            thread::spawn(move || {
+                thread::sleep(time::Duration::from_millis(100));
+                backend.set_dio_input(0, 0b0100);
+            })
+        };
+
+        let start = Instant::now();
+        continuous_read(&device, &RunConfig::default(), &run).unwrap();
+        trigger.join().unwrap();
+
+        assert!(start.elapsed().as_millis() < 500);
+        assert_eq!(run.stop_reason(), Some(StopReason::DioInput));
+    }
+
+    #[test]
+    fn test_sweep_counter() {
+        // 0 → 60000 → 0 → 60000 → 30000 の三角波に小さなノイズを乗せる
+        let mut positions = vec![];
+        for (from, to) in [(0, 60000), (60000, 0), (0, 60000), (60000, 30000)] {
+            let step = if to > from { 100 } else { -100 };
+            positions.extend((0..(to - from) / step).map(|i| from + i * step + (i % 3) * 500));
+        }
+
+        let mut counter = SweepCounter::default();
+        for chunk in positions.chunks(77) {
+            counter.update(chunk);
+        }
+        assert_eq!(counter.turns, 3);
+        assert_eq!(counter.sweeps(), 2);
+    }
+
+    #[test]
+    fn test_median_count() {
+        let dataset: Vec<_> = [1, 5, 3, 9, 7]
+            .iter()
+            .map(|len| RawDataset {
+                x: 0,
+                y: 0,
+                len: *len,
+            })
+            .collect();
+        assert_eq!(median_count(&dataset), 5);
+        assert_eq!(median_count(&[]), 0);
+    }
+
+    #[test]
+    fn test_get_data_faults() {
+        let backend = SimulatedBackend::new();
+        backend.inject(0, Fault::ShortData(1000), time::Duration::from_secs(0));
+        backend.inject(0, Fault::Overflow, time::Duration::from_millis(100));
+        backend.inject(0, Fault::UsbError, time::Duration::from_millis(300));
+        let device = Device::open(Arc::new(backend), 0).unwrap();
+        device.start(2, 0, 1, 0).unwrap();
+
+        let run = Arc::new(Run::default());
+        run.advance(RunState::Running);
+        let policy = PollPolicy::default();
+        let (result, dataset) = get_binned(&device, &run, QUEUE_CAPACITY, policy);
+
+        // 短いデータでも取り込みは続き、USBのエラーで止まる
+        assert!(matches!(result, Err(AdError::UsbError(_))), "{:?}", result);
+        assert_eq!(run.state(), RunState::Failed(result.unwrap_err()));
+        // CH1, CH2 それぞれのオーバーフロー
+        assert_eq!(run.overflows(), 2);
+        assert!(!dataset.is_empty());
+        assert!(run.samples() > 0);
+        assert_eq!(run.bins(), dataset.len() as u64);
+    }
+
+    /// `get_data` で取り込みながら別のスレッドでまとめ、結果とまとめたデータを返す
+    fn get_binned<B: AdBackend + ?Sized>(
+        device: &Device<B>,
+        run: &Arc<Run>,
+        capacity: usize,
+        policy: PollPolicy,
+    ) -> (Result<(), AdError>, Vec<RawDataset>) {
+        let queue = Arc::new(BlockQueue::new(capacity));
+        let dataset = Arc::new(Mutex::new(vec![]));
+        let binner = {
+            let (queue, run, dataset) = (Arc::clone(&queue), Arc::clone(run), Arc::clone(&dataset));
+            thread::spawn(move || {
+                bin_blocks(
+                    &queue,
+                    Channels::Both,
+                    PositionFilter::SavitzkyGolay {
+                        window: SAVITZKY_GOLAY_WINDOW,
+                    },
+                    &run,
+                    dataset,
+                )
+            })
+        };
+        let sender = BlockSender::new(vec![queue]);
+        let result = get_data(device, Channels::Both, 0, 100e3, run, sender, policy);
+        binner.join().unwrap();
+
+        let dataset = dataset.lock().unwrap();
+        (result, dataset.clone())
+    }
+
+    /// `get_data` を装置で動かし、収納されたデータを返す
+    fn acquire_for<B: AdBackend + ?Sized + 'static>(
+        device: &Arc<Device<B>>,
+        millis: u64,
+    ) -> Vec<RawDataset> {
+        let run = Arc::new(Run::default());
+        run.advance(RunState::Running);
+        let timer = stop_after(device, &run, millis);
+        // 再生では記録したブロックが実時間より速く届くので、全てが入る大きさにする
+        // 取り出す時点が時間で変わらないように、溜まった量だけで取り出す
+        let policy = PollPolicy {
+            target_block: 2000,
+            max_latency: Duration::from_secs(10),
+            ..PollPolicy::default()
+        };
+        let (result, dataset) = get_binned(device, &run, 1 << 16, policy);
+        result.unwrap();
+        timer.join().unwrap();
+        assert_eq!(run.dropped_blocks(), 0);
+        dataset
+    }
+
+    /// `continuous_read` と同じく、`millis` ミリ秒後に状態を進めてから装置を止める
+    fn stop_after<B: AdBackend + ?Sized + 'static>(
+        device: &Arc<Device<B>>,
+        run: &Arc<Run>,
+        millis: u64,
+    ) -> thread::JoinHandle<()> {
+        let (device, run) = (Arc::clone(device), Arc::clone(run));
+        thread::spawn(move || {
+            thread::sleep(time::Duration::from_millis(millis));
+            run.advance(RunState::Stopping);
+            device.stop().unwrap();
+        })
+    }
+
+    #[test]
+    fn test_drain_after_stop() {
+        let device = Arc::new(Device::open(Arc::new(SimulatedBackend::new()), 0).unwrap());
+        device.start(2, 0, 1, 0).unwrap();
+        let run = Arc::new(Run::default());
+        run.advance(RunState::Running);
+        let timer = {
+            let (device, run) = (Arc::clone(&device), Arc::clone(&run));
+            thread::spawn(move || {
+                thread::sleep(time::Duration::from_millis(50));
+                run.advance(RunState::Stopping);
+                // 状態を進めてから止めるまでの間にも変換は続く
+                thread::sleep(time::Duration::from_millis(20));
+                device.stop().unwrap();
+            })
+        };
+
+        // 途中では取り出さず、止めた後にまとめて取り出す
+        let queue = Arc::new(BlockQueue::new(QUEUE_CAPACITY));
+        let policy = PollPolicy {
+            target_block: 100000,
+            max_latency: Duration::from_secs(10),
+            ..PollPolicy::default()
+        };
+        let sender = BlockSender::new(vec![Arc::clone(&queue)]);
+        get_data(&device, Channels::Both, 0, 100e3, &run, sender, policy).unwrap();
+        timer.join().unwrap();
+
+        // 100 kHz で70 ms以上変換した分が全て届き、装置には残っていない
+        let mut length = 0;
+        queue.consume(|block| length += block.len());
+        assert!(length >= 7000, "{}", length);
+        assert_eq!(run.samples(), length as u64);
+        assert_eq!(device.status(false).unwrap().ch1_datalen, 0);
+    }
+
+    #[test]
+    fn test_poll_policy() {
+        let policy = PollPolicy {
+            target_block: 1000,
+            max_latency: Duration::from_millis(50),
+            ..PollPolicy::default()
+        };
+        // 100 kHz で残り500サンプル
+        let wait = policy.wait(500, Duration::from_millis(0), 100e3);
+        assert_eq!(wait, Some(Duration::from_millis(5)));
+        // 最大の待ち時間を超えて待たない
+        let wait = policy.wait(0, Duration::from_millis(45), 1e3);
+        assert_eq!(wait, Some(Duration::from_millis(5)));
+        assert_eq!(policy.wait(1000, Duration::from_millis(0), 100e3), None);
+        assert_eq!(policy.wait(0, Duration::from_millis(50), 100e3), None);
+
+        // バッファの半分が埋まる前に取り出す
+        let policy = PollPolicy {
+            target_block: 1 << 20,
+            max_latency: Duration::from_secs(10),
+            ..PollPolicy::default()
+        };
+        let limited = policy.limited(100e3, 262142);
+        assert_eq!(limited.target_block, 131071);
+        assert_eq!(limited.max_latency, Duration::from_secs_f64(1.31071));
+    }
+
+    #[test]
+    fn test_poll_target_block() {
+        let device = Arc::new(Device::open(Arc::new(SimulatedBackend::new()), 0).unwrap());
+        device.start(2, 0, 1, 0).unwrap();
+        let run = Arc::new(Run::default());
+        run.advance(RunState::Running);
+        let timer = stop_after(&device, &run, 300);
+
+        let queue = Arc::new(BlockQueue::new(QUEUE_CAPACITY));
+        let policy = PollPolicy {
+            target_block: 5000,
+            max_latency: Duration::from_secs(1),
+            ..PollPolicy::default()
+        };
+        let sender = BlockSender::new(vec![Arc::clone(&queue)]);
+        get_data(&device, Channels::Both, 0, 100e3, &run, sender, policy).unwrap();
+        timer.join().unwrap();
+
+        // 100 kHz で300 ms取り込むと、5000サンプル以上のブロックが数個になる
+        let mut lengths = vec![];
+        queue.consume(|block| lengths.push(block.len()));
+        // 最後のブロックは止めた後に残っていた分
+        assert!(!lengths.is_empty());
+        assert!(lengths.len() <= 7, "{:?}", lengths);
+        assert!(
+            lengths[..lengths.len() - 1]
+                .iter()
+                .all(|length| *length >= 5000),
+            "{:?}",
+            lengths
+        );
+    }
+
+    #[test]
+    fn test_record_blocks() {
+        let path =
+            std::env::temp_dir().join(format!("adconverter-blocks-{}.csv", std::process::id()));
+        let queue = BlockQueue::new(QUEUE_CAPACITY);
+        queue.push(Arc::new(Block::new(0, vec![1, 2], vec![3, 4], 1, 100e3)));
+        queue.push(Arc::new(Block::new(2, vec![], vec![5], 1, 100e3)));
+        queue.close();
+
+        record_blocks(&queue, path.to_str().unwrap());
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // トリガからのサンプル数と生の値。取り込んでいないチャネルは空
+        assert_eq!(written, "-1,1,3\n0,2,4\n1,,5\n");
+        assert!(queue.is_finished());
+    }
+
+    #[test]
+    fn test_replay_get_data() {
+        let path =
+            std::env::temp_dir().join(format!("adconverter-get-data-{}.jsonl", std::process::id()));
+        let recording = RecordingBackend::create(Arc::new(SimulatedBackend::new()), &path).unwrap();
+        let device = Arc::new(Device::open(Arc::new(recording), 0).unwrap());
+        device.start(2, 0, 1, 0).unwrap();
+        let recorded = acquire_for(&device, 100);
+        drop(device);
+
+        // 記録を同じ処理に通すと同じデータになる
+        let replay = ReplayBackend::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let device = Arc::new(Device::open(Arc::new(replay), 0).unwrap());
+        device.start(2, 0, 1, 0).unwrap();
+        let replayed = acquire_for(&device, 300);
+
+        assert!(!recorded.is_empty());
+        assert_eq!(replayed, recorded);
+    }
+
+    #[test]
+    fn test_block_trigger() {
+        let block = Block::new(100, vec![0; 50], vec![0; 50], 120, 100e3);
+        assert_eq!(block.trigger, Some(20));
+        assert_eq!(block.trigger_index, 120);
+
+        let block = Block::new(0, vec![0; 50], vec![0; 50], 120, 100e3);
+        assert_eq!(block.trigger, None);
+    }
+
+    #[test]
+    fn test_read_block() {
+        let device = Device::open(Arc::new(SimulatedBackend::new()), 0).unwrap();
+        // 100 kHz で、トリガ前の1000点は10 msで溜まる
+        device.start(2, 1000, 0, 0).unwrap();
+        thread::sleep(time::Duration::from_millis(20));
+        let device_status = device.status(false).unwrap();
+
+        // トリガ前はまだ取り出さない
+        let mut reader = BlockReader::new(Channels::Both, 1000, 100e3);
+        assert_eq!(reader.read(&device, &device_status).unwrap(), None);
+        reader.hold(&device_status);
+        assert!(reader.is_filled());
+
+        device.trigger().unwrap();
+        thread::sleep(time::Duration::from_millis(1));
+        let device_status = device.status(false).unwrap();
+        let block = reader.read(&device, &device_status).unwrap().unwrap();
+        assert_eq!(block.offset, 0);
+        assert_eq!(block.len(), device_status.ch1_datalen as usize);
+        assert_eq!(block.len(), block.ch2.len());
+        assert_eq!((block.trigger, block.trigger_index), (Some(1000), 1000));
+
+        let first_length = block.len();
+        thread::sleep(time::Duration::from_millis(1));
+        let device_status = device.status(false).unwrap();
+        let block = reader.read(&device, &device_status).unwrap().unwrap();
+        assert_eq!(block.offset, first_length);
+        assert_eq!((block.trigger, block.trigger_index), (None, 1000));
+    }
+
+    #[test]
+    fn test_trigger_before_filled() {
+        let device = Device::open(Arc::new(SimulatedBackend::new()), 0).unwrap();
+        // トリガ前の100000点が溜まる前にトリガを掛ける
+        device.start(2, 100000, 0, 0).unwrap();
+        thread::sleep(time::Duration::from_millis(5));
+        let device_status = device.status(false).unwrap();
+        let mut reader = BlockReader::new(Channels::Both, 100000, 100e3);
+        reader.hold(&device_status);
+        assert!(!reader.is_filled());
+        device.trigger().unwrap();
+
+        // トリガの位置は実際に溜まっていた数になる
+        thread::sleep(time::Duration::from_millis(10));
+        let device_status = device.status(false).unwrap();
+        let block = reader.read(&device, &device_status).unwrap().unwrap();
+        let held = device_status.datalen(Channels::Both) as usize;
+        assert_eq!(block.trigger, Some(block.trigger_index));
+        assert!(block.trigger_index >= 250 && block.trigger_index < held);
+    }
+
+    #[test]
+    fn test_read_single_channel() {
+        let device = Device::open(Arc::new(SimulatedBackend::new()), 0).unwrap();
+        // 100 kHz で、トリガ前の100点が溜まってからトリガが掛かる
+        device.start(1, 100, 1, 0).unwrap();
+        let mut reader = BlockReader::new(Channels::Ch2, 100, 100e3);
+        assert_eq!(reader.data2.len(), 2 * 262142);
+        thread::sleep(time::Duration::from_millis(120));
+        let device_status = device.status(false).unwrap();
+
+        let block = reader.read(&device, &device_status).unwrap().unwrap();
+        assert!(block.ch1.is_empty());
+        assert_eq!(block.len(), device_status.ch2_datalen as usize);
+        assert!(block.len() >= 10000);
+
+        let block = Block::new(0, vec![], block.ch2, 100, 100e3);
+        let mut dataset = vec![];
+        append_series(&block, &mut dataset);
+        assert_eq!(dataset.len(), block.len());
+        assert_eq!(dataset[0].x, -100);
+        assert_eq!(dataset[0].y, block.ch2[0]);
+    }
+
+    #[test]
+    fn test_rapid_scan_binning() {
+        let stage = Signal {
+            frequency: 5.0,
+            noise: 0.0,
+            ..Signal::default()
+        };
+        let pulse = ThzPulse {
+            center: 2.0,
+            width: 1.0,
+            ..ThzPulse::default()
+        };
+        let backend = SimulatedBackend::new()
+            .with_signal(0, stage)
+            .with_thz_pulse(pulse);
+        let device = Device::open(Arc::new(backend), 0).unwrap();
+
+        // 5 Hz の掃引の半周期以上を取り込む
+        device.start(2, 0, 1, 0).unwrap();
+        thread::sleep(time::Duration::from_millis(120));
+        let device_status = device.status(false).unwrap();
+        let mut reader = BlockReader::new(Channels::Both, 0, 100e3);
+        let block = reader.read(&device, &device_status).unwrap().unwrap();
+        device.stop().unwrap();
+
+        let dataset = Mutex::new(vec![]);
+        update_data(
+            &savitzky_golay(&block.ch1, SAVITZKY_GOLAY_WINDOW),
+            &block.ch2,
+            &mut dataset.lock().unwrap(),
+            block.len() as c_uint,
+        );
+
+        // フィルタの立ち上がりを除いて、各位置の値が真のパルスと一致する
+        let range = InputRange::Bipolar10V;
+        let dataset = dataset.lock().unwrap();
+        let (mut peak, mut trough) = (0.0f64, 0.0f64);
+        for data in dataset.iter().filter(|data| data.x > 1000) {
+            let position = range.to_volts(data.x as f32) as f64;
+            let volts = range.to_volts(data.y as f32) as f64;
+            if (-9.0..9.0).contains(&position) {
+                assert!((volts - pulse.level(position)).abs() < 0.05);
+            }
+            peak = peak.max(volts);
+            trough = trough.min(volts);
+        }
+        assert!((peak - 1.0).abs() < 0.05, "{}", peak);
+        assert!((trough + 1.0).abs() < 0.05, "{}", trough);
+    }
+
+    #[test]
+    fn test_phase() {
+        let phase = Phase::Idle.next(AcquisitionStatus::Stopped).unwrap();
+        assert_eq!(phase, Phase::Idle);
+
+        let phase = phase.next(AcquisitionStatus::WaitingForTrigger).unwrap();
+        assert_eq!(phase, Phase::Armed);
+
+        let phase = phase.next(AcquisitionStatus::Converting).unwrap();
+        assert_eq!(phase, Phase::Converting);
+
+        assert_eq!(
+            phase.next(AcquisitionStatus::Stopped),
+            Err(AdError::DeviceStopped("TUSB0216AD_Ad_Status"))
+        );
+        assert!(Phase::Armed.next(AcquisitionStatus::Stopped).is_err());
+    }
+
+    #[test]
+    fn test_count_overflows() {
+        let backend = Arc::new(SimulatedBackend::new());
+        let device = Device::open(Arc::clone(&backend), 0).unwrap();
+        let mut overflow = [false, false];
+
+        let device_status = device.status(false).unwrap();
+        assert_eq!(count_overflows(&mut overflow, &device_status), 0);
+
+        backend.set_overflow(0, [1, 1]);
+        let device_status = device.status(false).unwrap();
+        assert_eq!(count_overflows(&mut overflow, &device_status), 2);
+
+        // 続いているオーバーフローは数えない
+        let device_status = device.status(false).unwrap();
+        assert_eq!(count_overflows(&mut overflow, &device_status), 0);
+    }
+
+    #[test]
+    fn test_dio_lines() {
+        let backend = Arc::new(SimulatedBackend::new());
+        let device = Device::open(Arc::clone(&backend), 0).unwrap();
+        let run = Run::default();
+
+        device.dio_write(0b1000).unwrap();
+        set_dio_output(&device, 0, true).unwrap();
+        assert_eq!(device.dio_check().unwrap(), 0b1001);
+        set_dio_output(&device, 3, false).unwrap();
+        assert_eq!(device.dio_check().unwrap(), 0b0001);
+
+        let timeout = Duration::from_millis(100);
+        backend.set_dio_input(0, 0b0100);
+        assert!(wait_for_dio_input(&device, 2, timeout, &run).unwrap());
+
+        // 入力が来ないまま時間が過ぎた場合
+        let start = Instant::now();
+        assert_eq!(
+            wait_for_dio_input(&device, 1, timeout, &run),
+            Err(AdError::StageNotReady("STAGE_READY_DIO_LINE"))
+        );
+        assert!(start.elapsed() >= timeout);
+
+        // 入力が来ないまま中止された場合
+        run.cancel();
+        assert!(!wait_for_dio_input(&device, 1, Duration::MAX, &run).unwrap());
     }
 }
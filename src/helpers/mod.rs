@@ -1,2 +1,5 @@
+pub mod config;
 pub mod helper;
 pub mod post;
+pub mod queue;
+pub mod run;
@@ -1,57 +1,47 @@
 use reqwest;
 
-use crate::operations::interface;
+use super::config::RunConfig;
+use super::queue::BlockQueue;
+use super::run::{Run, StopReason};
+use crate::operations::{AdError, Channels, InputRange};
 use crate::RawDataset;
-use std::env;
-use std::os::raw::{c_short, c_uchar};
 use std::sync::{Arc, Mutex};
-use std::{thread, time};
+use std::thread;
 use tokio;
+use tokio::runtime::Runtime;
 
 #[derive(Serialize)]
 struct JsonData {
+    /// データを取り込んだ装置のID
+    id: i16,
+    /// サンプリング周波数 [Hz]
+    sample_rate: f64,
+    /// `true` なら `x` はトリガからの時刻 [s] で、前回のpost以降のデータのみを含む
+    time_series: bool,
     x: Vec<f32>,
     y: Vec<f32>,
+    /// 時系列の場合、トリガが掛かったサンプルの `x`, `y` での位置
+    /// このpostに含まれない場合や両チャネルの場合は `null`
+    trigger: Option<usize>,
+    /// 装置に溜まっていたトリガ前のサンプル数。トリガ後のデータを取り出すまでは `null`
+    pretrigger: Option<u64>,
     finished: bool,
-}
-
-/// CH1, CH2 にセットされているレンジの番号を取得する
-///
-/// # Argument
-///
-/// * id - 装置のユニット番号選択スイッチの数字
-fn get_ranges(id: c_short) -> (u8, u8) {
-    let mut ch1_range: u8 = 0;
-    let mut ch2_range: u8 = 0;
-
-    let ch1_range_ptr = &mut ch1_range as *mut c_uchar;
-    let ch2_range_ptr = &mut ch2_range as *mut c_uchar;
-    interface::input_check(id, ch1_range_ptr, ch2_range_ptr);
-
-    (ch1_range, ch2_range)
-}
-
-/// レンジの番号からレンジ幅を計算
-///
-/// # Argument
-///
-/// * range - レンジの番号。例えば0なら +/- 10 V
-fn calc_range_width(range: u8) -> f32 {
-    match range {
-        0 => 20.0,
-        1 | 4 => 10.0,
-        2 | 5 => 5.0,
-        3 | 6 => 2.5,
-        _ => 0.0,
-    }
+    /// 計測中に起きたバッファのオーバーフローの回数
+    overflows: u32,
+    /// 処理が追いつかずに捨てたブロックの数
+    dropped_blocks: u64,
+    /// オーバーフローや捨てたブロックでサンプルが失われたか
+    incomplete: bool,
+    /// 計測を終えた理由。終える前やエラーで終了した場合は `null`
+    stop_reason: Option<StopReason>,
 }
 
 /// 装置から得られた１点のストレートバイナリを電圧に変換
 ///
 /// # Arguments
 ///
-/// * ch1_range - CH1のレンジ番号
-/// * ch2_range - CH2のレンジ番号
+/// * ch1_range - CH1のレンジ
+/// * ch2_range - CH2のレンジ
 /// * ch1_data - CH1から得られたストレートバイナリ形式のデータ1点
 /// * ch2_data - CH2から得られたストレートバイナリ形式のデータ1点
 ///
@@ -59,94 +49,157 @@ fn calc_range_width(range: u8) -> f32 {
 ///
 /// CH1, CH2の出力電圧値
 pub fn convert_to_voltage(
-    ch1_range: u8,
-    ch2_range: u8,
+    ch1_range: InputRange,
+    ch2_range: InputRange,
     ch1_data: f32,
     ch2_data: f32,
 ) -> (f32, f32) {
-    let ch1_width: f32 = calc_range_width(ch1_range);
-    let ch2_width: f32 = calc_range_width(ch2_range);
-
-    let ch1_result: f32;
-    let ch2_result: f32;
-    let max_val = 2f32.powf(16.0) - 1.0;
-    if ch1_range > 3 {
-        ch1_result = ch1_data * ch1_width / max_val;
-    } else {
-        ch1_result = ch1_data * ch1_width / max_val - ch1_width / 2.0;
+    (ch1_range.to_volts(ch1_data), ch2_range.to_volts(ch2_data))
+}
+
+/// postするデータを取り出す。ロックしている間はまとめる処理が止まるので、コピーするだけにする
+/// 両チャネルの場合はCH1の値ごとのCH2の平均を全て複製し、
+/// 1チャネルの場合はたまっている時系列を取り出す
+///
+/// # Arguments
+///
+/// * dataset - `bin_blocks` が収納したデータ
+/// * channels - 取り込んだチャネル
+fn snapshot(dataset: &Mutex<Vec<RawDataset>>, channels: Channels) -> Vec<RawDataset> {
+    let mut dataset = dataset.lock().unwrap();
+    match channels {
+        Channels::Both => dataset.clone(),
+        _ => std::mem::take(&mut *dataset),
     }
+}
 
-    if ch2_range > 3 {
-        ch2_result = ch2_data * ch2_width / max_val;
-    } else {
-        ch2_result = ch2_data * ch2_width / max_val - ch2_width / 2.0;
+/// 時系列の中でトリガが掛かったサンプルの位置。`x` はトリガからのサンプル数なので0の点になる
+///
+/// # Arguments
+///
+/// * dataset - `snapshot` で取り出したデータ
+/// * channels - 取り込んだチャネル
+fn trigger_position(dataset: &[RawDataset], channels: Channels) -> Option<usize> {
+    match channels {
+        Channels::Both => None,
+        _ => dataset.iter().position(|data| data.x == 0),
     }
+}
 
-    return (ch1_result, ch2_result);
+/// postするデータを電圧に変換する
+/// 両チャネルの場合はCH1の値ごとのCH2の平均を、1チャネルの場合は時系列を時刻と電圧にする
+///
+/// # Arguments
+///
+/// * dataset - `snapshot` で取り出したデータ
+/// * channels - 取り込んだチャネル
+/// * range - CH1, CH2のレンジ
+/// * sample_rate - サンプリング周波数 [Hz]
+fn to_points(
+    dataset: &[RawDataset],
+    channels: Channels,
+    range: (InputRange, InputRange),
+    sample_rate: f64,
+) -> (Vec<f32>, Vec<f32>) {
+    let series_range = match channels {
+        Channels::Both => {
+            return dataset
+                .iter()
+                .map(|data| convert_to_voltage(range.0, range.1, data.x as f32, data.y as f32))
+                .unzip();
+        }
+        Channels::Ch1 => range.0,
+        Channels::Ch2 => range.1,
+    };
+
+    dataset
+        .iter()
+        .map(|data| {
+            let time = (data.x as f64 / sample_rate) as f32;
+            (time, series_range.to_volts(data.y as f32))
+        })
+        .unzip()
 }
 
-pub fn post_data(id: c_short, flag: Arc<Mutex<i8>>, dataset: Arc<Mutex<Vec<RawDataset>>>) {
-    let range: (c_uchar, c_uchar) = get_ranges(id);
+/// まとめたデータを定期的に電圧に変換してpostする
+/// `binning` の処理が終わると、残りのデータを `finished` としてpostして終了する
+/// 電圧への変換には、装置に問い合わせずに設定した入力レンジを使う
+///
+/// # Arguments
+///
+/// * config - 計測の設定。装置のID、入力レンジ、チャネル、postするURLと間隔を使う
+/// * sample_rate - サンプリング周波数 [Hz]
+/// * run - 計測の状態
+/// * binning - `bin_blocks` が読んでいるキュー
+/// * dataset - `bin_blocks` が収納したデータ
+pub fn post_data(
+    config: &RunConfig,
+    sample_rate: f64,
+    run: &Run,
+    binning: &BlockQueue,
+    dataset: Arc<Mutex<Vec<RawDataset>>>,
+) -> Result<(), AdError> {
+    let channels = config.channels;
+    let sinks = &config.sinks;
+    let url = match sinks.post_url.as_deref() {
+        Some(url) => url,
+        None => {
+            // 送り先がないとデータを渡せないので計測を止める
+            let e = AdError::InvalidParameters("post_url");
+            run.fail(e);
+            return Err(e);
+        }
+    };
     let rt = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()
         .unwrap();
 
     println!("Start posting!");
-    loop {
-        if *flag.lock().unwrap() != -1 {
-            break;
-        }
-        thread::sleep(time::Duration::from_millis(1));
-    }
+    // 取り込みが始まらずに終わった場合も、終了を知らせるために一度はpostする
+    run.wait_running();
     println!("Send json!");
 
     let client = reqwest::Client::new();
-    let url = env::var("DATA_POST_URL").expect("DATA_POST_URL is not set");
     loop {
-        thread::sleep(time::Duration::from_millis(300));
-        let dataset = dataset.lock().unwrap();
-        let mut xx: Vec<f32> = Vec::new();
-        let mut yy: Vec<f32> = Vec::new();
-
-        for i in 0..dataset.len() {
-            let voltage =
-                convert_to_voltage(range.0, range.1, dataset[i].x as f32, dataset[i].y as f32);
-
-            xx.push(voltage.0);
-            yy.push(voltage.1);
-        }
-
-        if *flag.lock().unwrap() == 1 {
-            rt.block_on(async {
-                let data = JsonData {
-                    x: xx,
-                    y: yy,
-                    finished: true,
-                };
-                let _response = client
-                    .post(&url)
-                    .json(&data)
-                    .send()
-                    .await
-                    .expect("Failed to post json");
-            });
+        thread::sleep(sinks.post_interval);
+        // まとめ終えたかを先に確認し、最後のpostに全てのデータが入るようにする
+        let finished = binning.is_finished();
+        let points = snapshot(&dataset, channels);
+        let trigger = trigger_position(&points, channels);
+        let (xx, yy) = to_points(&points, channels, config.ranges, sample_rate);
+        let overflow_count = run.overflows() as u32;
+        let dropped_blocks = run.dropped_blocks();
+
+        let data = JsonData {
+            id: config.id,
+            sample_rate,
+            time_series: channels != Channels::Both,
+            x: xx,
+            y: yy,
+            trigger,
+            pretrigger: run.pretrigger(),
+            finished,
+            overflows: overflow_count,
+            dropped_blocks,
+            incomplete: overflow_count > 0 || dropped_blocks > 0,
+            stop_reason: run.stop_reason(),
+        };
+        send_json(&rt, &client, url, &data);
+
+        if finished {
             break;
         }
+    }
+    Ok(())
+}
 
-        rt.block_on(async {
-            let data = JsonData {
-                x: xx,
-                y: yy,
-                finished: false,
-            };
-            let _response = client
-                .post(&url)
-                .json(&data)
-                .send()
-                .await
-                .expect("Failed to post json");
-        });
+/// `data` を `url` にpostする
+/// 送れなかった場合も取り込みは続けられるので、表示するだけにする
+fn send_json(rt: &Runtime, client: &reqwest::Client, url: &str, data: &JsonData) {
+    let result = rt.block_on(client.post(url).json(data).send());
+    if let Err(e) = result {
+        println!("Failed to post json: {}", e);
     }
 }
 
@@ -168,65 +221,112 @@ mod test {
 
     #[test]
     fn test_converting_voltage() {
-        let res1 = convert_to_voltage(0, 0, 0.0, 65535.0); // 65535 = FFFF
+        let res1 = convert_to_voltage(InputRange::Bipolar10V, InputRange::Bipolar10V, 0.0, 65535.0); // 65535 = FFFF
         assert_eq!(res1.0, -10.0);
         assert_eq!(res1.1, 10.0);
 
-        let res2 = convert_to_voltage(1, 1, 0.0, 65535.0);
+        let res2 = convert_to_voltage(InputRange::Bipolar5V, InputRange::Bipolar5V, 0.0, 65535.0);
         assert_eq!(res2.0, -5.0);
         assert_eq!(res2.1, 5.0);
 
-        let res3 = convert_to_voltage(2, 2, 0.0, 65535.0);
+        let res3 = convert_to_voltage(
+            InputRange::Bipolar2_5V,
+            InputRange::Bipolar2_5V,
+            0.0,
+            65535.0,
+        );
         assert_eq!(res3.0, -2.5);
         assert_eq!(res3.1, 2.5);
 
-        let res4 = convert_to_voltage(3, 3, 0.0, 65535.0);
+        let res4 = convert_to_voltage(
+            InputRange::Bipolar1_25V,
+            InputRange::Bipolar1_25V,
+            0.0,
+            65535.0,
+        );
         assert_eq!(res4.0, -1.25);
         assert_eq!(res4.1, 1.25);
 
-        let res5 = convert_to_voltage(4, 4, 0.0, 65535.0);
+        let res5 = convert_to_voltage(
+            InputRange::Unipolar10V,
+            InputRange::Unipolar10V,
+            0.0,
+            65535.0,
+        );
         assert_eq!(res5.0, 0.0);
         assert_eq!(res5.1, 10.0);
 
-        let res6 = convert_to_voltage(5, 5, 0.0, 65535.0);
+        let res6 = convert_to_voltage(InputRange::Unipolar5V, InputRange::Unipolar5V, 0.0, 65535.0);
         assert_eq!(res6.0, 0.0);
         assert_eq!(res6.1, 5.0);
 
-        let res7 = convert_to_voltage(6, 6, 0.0, 65535.0);
+        let res7 = convert_to_voltage(
+            InputRange::Unipolar2_5V,
+            InputRange::Unipolar2_5V,
+            0.0,
+            65535.0,
+        );
         assert_eq!(res7.0, 0.0);
         assert_eq!(res7.1, 2.5);
     }
 
     #[test]
-    fn test_get_range() {
-        let range = get_ranges(2);
+    fn test_to_points() {
+        let range = (InputRange::Bipolar10V, InputRange::Unipolar10V);
+        let dataset = Mutex::new(vec![
+            RawDataset { x: 0, y: 0, len: 2 },
+            RawDataset {
+                x: 65535,
+                y: 65535,
+                len: 1,
+            },
+        ]);
+
+        // 位置ごとにまとめたデータは残しておく
+        let points = snapshot(&dataset, Channels::Both);
+        let (x, y) = to_points(&points, Channels::Both, range, 100e3);
+        assert_eq!(x, vec![-10.0, 10.0]);
+        assert_eq!(y, vec![0.0, 10.0]);
+        assert_eq!(dataset.lock().unwrap().len(), 2);
+
+        // 時系列は取り出す
+        let points = snapshot(&dataset, Channels::Ch2);
+        let (x, y) = to_points(&points, Channels::Ch2, range, 100e3);
+        assert_eq!(x, vec![0.0, 0.65535]);
+        assert_eq!(y, vec![0.0, 10.0]);
+        assert!(dataset.lock().unwrap().is_empty());
+    }
 
-        assert_eq!(range.0, 0);
-        assert_eq!(range.1, 0);
+    #[test]
+    fn test_trigger_position() {
+        let dataset: Vec<RawDataset> = (-2..3).map(|x| RawDataset { x, y: 0, len: 1 }).collect();
+        assert_eq!(trigger_position(&dataset, Channels::Ch1), Some(2));
+        assert_eq!(trigger_position(&dataset[3..], Channels::Ch1), None);
+        assert_eq!(trigger_position(&dataset, Channels::Both), None);
     }
 
     /// 0: +/-10V, 1: +/-5V, 2: +/-2.5V, 3: +/-1.25V, 4: 10V, 5: 5V, 6: 2.5V
     #[test]
     fn test_calc_width() {
-        let width = calc_range_width(0); // +/-10 V
+        let width = InputRange::Bipolar10V.width(); // +/-10 V
         assert_eq!(width, 20.0);
 
-        let width = calc_range_width(1); // +/-5 V
+        let width = InputRange::Bipolar5V.width(); // +/-5 V
         assert_eq!(width, 10.0);
 
-        let width = calc_range_width(2); // +/-2.5 V
+        let width = InputRange::Bipolar2_5V.width(); // +/-2.5 V
         assert_eq!(width, 5.0);
 
-        let width = calc_range_width(3); // +/-1.25 V
+        let width = InputRange::Bipolar1_25V.width(); // +/-1.25 V
         assert_eq!(width, 2.5);
 
-        let width = calc_range_width(4); // 10 V
+        let width = InputRange::Unipolar10V.width(); // 10 V
         assert_eq!(width, 10.0);
 
-        let width = calc_range_width(5); // 5 V
+        let width = InputRange::Unipolar5V.width(); // 5 V
         assert_eq!(width, 5.0);
 
-        let width = calc_range_width(6); // 2.5 V
+        let width = InputRange::Unipolar2_5V.width(); // 2.5 V
         assert_eq!(width, 2.5);
     }
 }
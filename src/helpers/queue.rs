@@ -0,0 +1,198 @@
+use super::helper::Block;
+use super::run::Run;
+use crossbeam_queue::ArrayQueue;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::{thread, time};
+
+/// 1つのキューに溜めておけるブロックの数
+pub const QUEUE_CAPACITY: usize = 256;
+
+/// 取り込みスレッドから、ブロックを処理するスレッドへブロックを渡すキュー
+/// 処理が追いつかずにいっぱいになった場合は、新しいブロックを捨てて数える
+#[derive(Debug)]
+pub struct BlockQueue {
+    queue: ArrayQueue<Arc<Block>>,
+    /// 取り込みが終わり、これ以上ブロックが来ない
+    closed: AtomicBool,
+    /// 処理するスレッドが全てのブロックを処理し終えた
+    finished: AtomicBool,
+    /// いっぱいで捨てたブロックの数
+    dropped: AtomicU64,
+    /// これまでに溜まったブロックの最大数
+    max_depth: AtomicUsize,
+}
+
+impl BlockQueue {
+    pub fn new(capacity: usize) -> Self {
+        BlockQueue {
+            queue: ArrayQueue::new(capacity),
+            closed: AtomicBool::new(false),
+            finished: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+            max_depth: AtomicUsize::new(0),
+        }
+    }
+
+    /// ブロックを追加する。いっぱいの場合は捨てて `false` を返す
+    pub fn push(&self, block: Arc<Block>) -> bool {
+        if self.queue.push(block).is_err() {
+            self.dropped.fetch_add(1, Ordering::SeqCst);
+            return false;
+        }
+        self.max_depth.fetch_max(self.queue.len(), Ordering::SeqCst);
+        true
+    }
+
+    /// 次のブロックが来るまで待つ
+    /// 閉じられていて残っているブロックがなければ `None` を返す
+    pub fn next(&self) -> Option<Arc<Block>> {
+        loop {
+            if let Some(block) = self.queue.pop() {
+                return Some(block);
+            }
+            if self.closed.load(Ordering::SeqCst) {
+                // 閉じる直前に追加されたブロックを取りこぼさない
+                return self.queue.pop();
+            }
+            thread::sleep(time::Duration::from_millis(1));
+        }
+    }
+
+    /// キューが閉じられて空になるまで、ブロックを1つずつ `f` で処理する
+    /// 処理を終えると、パニックした場合も含めて `is_finished` が `true` になる
+    pub fn consume<F: FnMut(&Block)>(&self, mut f: F) {
+        let _finish = Finish(self);
+        while let Some(block) = self.next() {
+            f(&block);
+        }
+    }
+
+    /// これ以上ブロックを追加しないことを知らせる
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::SeqCst)
+    }
+
+    /// 溜まっているブロックの数
+    pub fn depth(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::SeqCst)
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth.load(Ordering::SeqCst)
+    }
+}
+
+/// 処理を終えたことを、スコープを抜けるときに知らせる
+struct Finish<'a>(&'a BlockQueue);
+
+impl Drop for Finish<'_> {
+    fn drop(&mut self) {
+        self.0.finished.store(true, Ordering::SeqCst);
+    }
+}
+
+/// 取り込んだブロックを全てのキューに渡す
+/// 取り込みが終わって破棄されるときに全てのキューを閉じる
+#[derive(Debug, Default)]
+pub struct BlockSender {
+    queues: Vec<Arc<BlockQueue>>,
+}
+
+impl BlockSender {
+    pub fn new(queues: Vec<Arc<BlockQueue>>) -> Self {
+        BlockSender { queues }
+    }
+
+    /// `block` を全てのキューに追加し、キューの状況を `run` に反映する
+    pub fn send(&self, block: Block, run: &Run) {
+        let block = Arc::new(block);
+        let dropped = self
+            .queues
+            .iter()
+            .filter(|queue| !queue.push(Arc::clone(&block)))
+            .count();
+        if dropped > 0 {
+            println!("Block queue is full, {} block(s) dropped", dropped);
+            run.add_dropped_blocks(dropped);
+        }
+        let depth = self.queues.iter().map(|queue| queue.depth()).max();
+        run.set_queue_depth(depth.unwrap_or(0));
+    }
+}
+
+impl Drop for BlockSender {
+    fn drop(&mut self) {
+        for queue in &self.queues {
+            queue.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn block(offset: usize) -> Block {
+        Block::new(offset, vec![0; 10], vec![0; 10], 0, 100e3)
+    }
+
+    #[test]
+    fn test_queue_full() {
+        let run = Run::default();
+        let queue = Arc::new(BlockQueue::new(2));
+        let sender = BlockSender::new(vec![Arc::clone(&queue)]);
+        for offset in 0..3 {
+            sender.send(block(offset * 10), &run);
+        }
+
+        // いっぱいになった後のブロックは捨てられる
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(queue.max_depth(), 2);
+        assert_eq!(run.dropped_blocks(), 1);
+        assert_eq!(run.queue_depth(), 2);
+
+        drop(sender);
+        let mut offsets = vec![];
+        queue.consume(|block| offsets.push(block.offset));
+        assert_eq!(offsets, vec![0, 10]);
+        assert!(queue.is_finished());
+    }
+
+    #[test]
+    fn test_consume_concurrently() {
+        let run = Run::default();
+        let queues: Vec<_> = (0..2).map(|_| Arc::new(BlockQueue::new(1000))).collect();
+        let consumers: Vec<_> = queues
+            .iter()
+            .map(|queue| {
+                let queue = Arc::clone(queue);
+                thread::spawn(move || {
+                    let mut samples = 0;
+                    queue.consume(|block| samples += block.len());
+                    samples
+                })
+            })
+            .collect();
+
+        let sender = BlockSender::new(queues.clone());
+        for offset in 0..1000 {
+            sender.send(block(offset * 10), &run);
+        }
+        drop(sender);
+
+        // 全ての読み手が全てのブロックを受け取る
+        for consumer in consumers {
+            assert_eq!(consumer.join().unwrap(), 10000);
+        }
+        assert!(queues.iter().all(|queue| queue.is_finished()));
+    }
+}
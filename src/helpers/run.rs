@@ -0,0 +1,550 @@
+use crate::operations::AdError;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// 計測の状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunState {
+    /// 計測の開始前
+    Idle,
+    /// 装置の設定中、またはステージやトリガを待っている
+    Arming,
+    /// 連続取り込み中
+    Running,
+    /// 連続取り込みを止めている
+    Stopping,
+    /// 正常に終了した
+    Finished,
+    /// エラーで終了した
+    Failed(AdError),
+}
+
+impl RunState {
+    /// C から参照する状態の番号
+    /// 0: Idle, 1: Arming, 2: Running, 3: Stopping, 4: Finished, 5: Failed
+    pub fn code(self) -> u8 {
+        match self {
+            RunState::Failed(_) => 5,
+            state => state.order(),
+        }
+    }
+
+    /// 計測が終了しているか
+    pub fn is_done(self) -> bool {
+        self.order() == 4
+    }
+
+    /// 状態の進み具合。状態は後戻りしない
+    fn order(self) -> u8 {
+        match self {
+            RunState::Idle => 0,
+            RunState::Arming => 1,
+            RunState::Running => 2,
+            RunState::Stopping => 3,
+            RunState::Finished | RunState::Failed(_) => 4,
+        }
+    }
+}
+
+/// 計測を終える条件。設定した条件のいずれかを満たした時点で終える
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StopConditions {
+    /// 連続取り込みを始めてからの時間
+    pub duration: Option<Duration>,
+    /// 取り込むサンプル数。ちょうどこの数で打ち切る
+    pub samples: Option<u64>,
+    /// ステージが端から端まで動いた回数
+    pub sweeps: Option<u64>,
+    /// 半分以上の位置でこの数以上のサンプルが平均された
+    pub bin_count: Option<u64>,
+    /// デジタル入力のこのビットがHighになった
+    pub dio_line: Option<u8>,
+}
+
+impl StopConditions {
+    /// `seconds` 秒だけ取り込む
+    pub fn duration(seconds: u64) -> Self {
+        StopConditions {
+            duration: Some(Duration::from_secs(seconds)),
+            ..StopConditions::default()
+        }
+    }
+
+    /// C から渡された値から作る。0の条件と負の `dio_line` は使わない
+    /// 条件が1つもない場合や `dio_line` が7より大きい場合は `InvalidParameters` を返す
+    pub fn from_raw(
+        seconds: u64,
+        samples: u64,
+        sweeps: u64,
+        bin_count: u64,
+        dio_line: i16,
+    ) -> Result<Self, AdError> {
+        let nonzero = |value: u64| Some(value).filter(|value| *value > 0);
+        let conditions = StopConditions {
+            duration: nonzero(seconds).map(Duration::from_secs),
+            samples: nonzero(samples),
+            sweeps: nonzero(sweeps),
+            bin_count: nonzero(bin_count),
+            dio_line: u8::try_from(dio_line).ok(),
+        };
+
+        match conditions.dio_line {
+            Some(line) if line >= 8 => Err(AdError::InvalidParameters("run_until")),
+            _ if conditions == StopConditions::default() => {
+                Err(AdError::InvalidParameters("run_until"))
+            }
+            _ => Ok(conditions),
+        }
+    }
+
+    /// 取り込みの進み具合から満たした条件を返す
+    /// サンプル数は取り込みスレッドが、デジタル入力は装置を読むスレッドが確認する
+    ///
+    /// # Arguments
+    ///
+    /// * run - 計測の状態
+    /// * elapsed - 連続取り込みを始めてからの時間
+    pub fn reached(&self, run: &Run, elapsed: Duration) -> Option<StopReason> {
+        let reached =
+            |limit: Option<u64>, value: u64| matches!(limit, Some(limit) if value >= limit);
+
+        if matches!(self.duration, Some(duration) if elapsed >= duration) {
+            Some(StopReason::Duration)
+        } else if reached(self.sweeps, run.sweeps()) {
+            Some(StopReason::Sweeps)
+        } else if reached(self.bin_count, run.bin_count()) {
+            Some(StopReason::BinCount)
+        } else {
+            None
+        }
+    }
+}
+
+/// 計測を終えた理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    Duration,
+    Samples,
+    Sweeps,
+    BinCount,
+    DioInput,
+    /// `abort_run` などで中止された
+    Cancelled,
+}
+
+impl StopReason {
+    /// C から参照する番号。0は終える条件をまだ満たしていないことを表す
+    pub fn code(self) -> u8 {
+        match self {
+            StopReason::Duration => 1,
+            StopReason::Samples => 2,
+            StopReason::Sweeps => 3,
+            StopReason::BinCount => 4,
+            StopReason::DioInput => 5,
+            StopReason::Cancelled => 6,
+        }
+    }
+}
+
+/// 計測を中止するためのトークン
+#[derive(Debug, Default)]
+pub struct CancelToken {
+    cancelled: AtomicBool,
+}
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// 計測を行うスレッド間で共有する状態と中止の要求
+#[derive(Debug)]
+pub struct Run {
+    state: Mutex<RunState>,
+    changed: Condvar,
+    token: CancelToken,
+    created: Instant,
+    /// 終了までにかかった時間
+    duration: Mutex<Option<Duration>>,
+    /// 取り込んだサンプル数
+    samples: AtomicU64,
+    /// データが入っている位置の数
+    bins: AtomicU64,
+    /// ブロックのキューに溜まっている数
+    queue_depth: AtomicU64,
+    /// キューがいっぱいで捨てたブロックの数
+    dropped_blocks: AtomicU64,
+    /// 装置のバッファのオーバーフローの回数
+    overflows: AtomicU64,
+    /// 計測を終える条件
+    conditions: StopConditions,
+    /// 計測を終えた理由
+    reason: Mutex<Option<StopReason>>,
+    /// ステージが端から端まで動いた回数
+    sweeps: AtomicU64,
+    /// 半分以上の位置で平均されたサンプル数
+    bin_count: AtomicU64,
+    /// 装置に溜まっていたトリガ前のサンプル数。トリガ後に取り出すまでは `None`
+    pretrigger: Mutex<Option<u64>>,
+}
+
+impl Default for Run {
+    fn default() -> Self {
+        Run {
+            state: Mutex::new(RunState::Idle),
+            changed: Condvar::new(),
+            token: CancelToken::default(),
+            created: Instant::now(),
+            duration: Mutex::new(None),
+            samples: AtomicU64::new(0),
+            bins: AtomicU64::new(0),
+            queue_depth: AtomicU64::new(0),
+            dropped_blocks: AtomicU64::new(0),
+            overflows: AtomicU64::new(0),
+            conditions: StopConditions::default(),
+            reason: Mutex::new(None),
+            sweeps: AtomicU64::new(0),
+            bin_count: AtomicU64::new(0),
+            pretrigger: Mutex::new(None),
+        }
+    }
+}
+
+impl Run {
+    /// `conditions` のいずれかを満たすまで取り込む計測を作る
+    pub fn with_conditions(conditions: StopConditions) -> Self {
+        Run {
+            conditions,
+            ..Run::default()
+        }
+    }
+
+    pub fn conditions(&self) -> StopConditions {
+        self.conditions
+    }
+
+    /// 条件を満たしたので、理由を残して取り込みを止める
+    pub fn stop(&self, reason: StopReason) {
+        self.reason.lock().unwrap().get_or_insert(reason);
+        self.advance(RunState::Stopping);
+    }
+
+    /// 計測を終えた理由。エラーで終了した場合や、まだ終えていない場合は `None`
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        *self.reason.lock().unwrap()
+    }
+
+    pub fn state(&self) -> RunState {
+        *self.state.lock().unwrap()
+    }
+
+    /// 状態を `state` に進める。既に先の状態にある場合は何もしない
+    pub fn advance(&self, state: RunState) {
+        let mut current = self.state.lock().unwrap();
+        if state.order() > current.order() {
+            if state.is_done() {
+                *self.duration.lock().unwrap() = Some(self.created.elapsed());
+            }
+            *current = state;
+            self.changed.notify_all();
+        }
+    }
+
+    /// エラーで終了させる。最初のエラーだけが残る
+    pub fn fail(&self, error: AdError) {
+        self.advance(RunState::Failed(error));
+    }
+
+    /// 計測の中止を要求する
+    pub fn cancel(&self) {
+        if !self.state().is_done() {
+            self.reason
+                .lock()
+                .unwrap()
+                .get_or_insert(StopReason::Cancelled);
+        }
+        self.token.cancel();
+        // 待っているスレッドに中止を知らせる
+        let _state = self.state.lock().unwrap();
+        self.changed.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// 計測を作ってからの時間。終了後は終了までにかかった時間
+    pub fn elapsed(&self) -> Duration {
+        self.duration
+            .lock()
+            .unwrap()
+            .unwrap_or_else(|| self.created.elapsed())
+    }
+
+    /// 取り込んだサンプル数に `samples` を足す
+    pub fn add_samples(&self, samples: usize) {
+        self.samples.fetch_add(samples as u64, Ordering::SeqCst);
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.samples.load(Ordering::SeqCst)
+    }
+
+    /// データが入っている位置の数を更新する
+    pub fn set_bins(&self, bins: usize) {
+        self.bins.store(bins as u64, Ordering::SeqCst);
+    }
+
+    pub fn bins(&self) -> u64 {
+        self.bins.load(Ordering::SeqCst)
+    }
+
+    /// ブロックのキューに溜まっている数を更新する
+    pub fn set_queue_depth(&self, depth: usize) {
+        self.queue_depth.store(depth as u64, Ordering::SeqCst);
+    }
+
+    pub fn queue_depth(&self) -> u64 {
+        self.queue_depth.load(Ordering::SeqCst)
+    }
+
+    /// 捨てたブロックの数に `blocks` を足す
+    pub fn add_dropped_blocks(&self, blocks: usize) {
+        self.dropped_blocks
+            .fetch_add(blocks as u64, Ordering::SeqCst);
+    }
+
+    pub fn dropped_blocks(&self) -> u64 {
+        self.dropped_blocks.load(Ordering::SeqCst)
+    }
+
+    /// オーバーフローの回数に `count` を足す
+    pub fn add_overflows(&self, count: u32) {
+        self.overflows.fetch_add(count as u64, Ordering::SeqCst);
+    }
+
+    pub fn overflows(&self) -> u64 {
+        self.overflows.load(Ordering::SeqCst)
+    }
+
+    /// ステージが端から端まで動いた回数を更新する
+    pub fn set_sweeps(&self, sweeps: u64) {
+        self.sweeps.store(sweeps, Ordering::SeqCst);
+    }
+
+    pub fn sweeps(&self) -> u64 {
+        self.sweeps.load(Ordering::SeqCst)
+    }
+
+    /// 半分以上の位置で平均されたサンプル数を更新する
+    pub fn set_bin_count(&self, count: u64) {
+        self.bin_count.store(count, Ordering::SeqCst);
+    }
+
+    pub fn bin_count(&self) -> u64 {
+        self.bin_count.load(Ordering::SeqCst)
+    }
+
+    /// トリガ後に取り出したデータから分かった、トリガ前のサンプル数を残す
+    pub fn set_pretrigger(&self, samples: usize) {
+        *self.pretrigger.lock().unwrap() = Some(samples as u64);
+    }
+
+    pub fn pretrigger(&self) -> Option<u64> {
+        *self.pretrigger.lock().unwrap()
+    }
+
+    /// 取り込みを終えるべきか。止めている最中以降か、中止が要求された場合
+    pub fn should_stop(&self) -> bool {
+        self.is_cancelled() || self.state().order() >= RunState::Stopping.order()
+    }
+
+    /// 連続取り込みが始まるまで待つ
+    /// 始まらずに終えるべき状態になった場合は `false` を返す
+    pub fn wait_running(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if self.is_cancelled() || state.order() >= RunState::Stopping.order() {
+                return false;
+            }
+            if *state == RunState::Running {
+                return true;
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    /// `timeout` が過ぎるか終えるべき状態になるまで待つ
+    /// 終えるべき状態になった場合は `false` を返す
+    pub fn sleep(&self, timeout: Duration) -> bool {
+        // 表せないほど長い場合は期限なしで待つ
+        let deadline = Instant::now().checked_add(timeout);
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if self.is_cancelled() || state.order() >= RunState::Stopping.order() {
+                return false;
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return true;
+                    }
+                    self.changed.wait_timeout(state, deadline - now).unwrap().0
+                }
+                None => self.changed.wait(state).unwrap(),
+            };
+        }
+    }
+
+    /// 計測が終了するまで最大 `timeout` だけ待ち、終了していればその状態を返す
+    pub fn wait_done(&self, timeout: Duration) -> Option<RunState> {
+        // 表せないほど長い場合は期限なしで待つ
+        let deadline = Instant::now().checked_add(timeout);
+        let mut state = self.state.lock().unwrap();
+        while !state.is_done() {
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return None;
+                    }
+                    self.changed.wait_timeout(state, deadline - now).unwrap().0
+                }
+                None => self.changed.wait(state).unwrap(),
+            };
+        }
+        Some(*state)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_advance() {
+        let run = Run::default();
+        run.advance(RunState::Running);
+        run.advance(RunState::Arming);
+        assert_eq!(run.state(), RunState::Running);
+
+        run.fail(AdError::UsbError("TUSB0216AD_Ad_Status"));
+        run.fail(AdError::UsbError("TUSB0216AD_Stop"));
+        run.advance(RunState::Finished);
+        assert_eq!(
+            run.state(),
+            RunState::Failed(AdError::UsbError("TUSB0216AD_Ad_Status"))
+        );
+        assert!(run.should_stop());
+    }
+
+    #[test]
+    fn test_cancel_wakes_sleep() {
+        let run = Arc::new(Run::default());
+        let sleeper = {
+            let run = Arc::clone(&run);
+            // 表せないほど長くても期限なしで待つ
+            thread::spawn(move || run.sleep(Duration::MAX))
+        };
+        thread::sleep(Duration::from_millis(20));
+        run.cancel();
+
+        assert!(!sleeper.join().unwrap());
+        assert!(run.should_stop());
+        assert_eq!(run.stop_reason(), Some(StopReason::Cancelled));
+        assert!(!run.wait_running());
+        assert!(Run::default().sleep(Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn test_wait_running() {
+        let run = Arc::new(Run::default());
+        let waiter = {
+            let run = Arc::clone(&run);
+            thread::spawn(move || run.wait_running())
+        };
+        run.advance(RunState::Arming);
+        run.advance(RunState::Running);
+        assert!(waiter.join().unwrap());
+    }
+
+    #[test]
+    fn test_wait_done() {
+        let run = Arc::new(Run::default());
+        assert_eq!(run.wait_done(Duration::from_millis(10)), None);
+
+        let finisher = {
+            let run = Arc::clone(&run);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                run.advance(RunState::Finished);
+            })
+        };
+        assert_eq!(
+            run.wait_done(Duration::from_secs(10)),
+            Some(RunState::Finished)
+        );
+        finisher.join().unwrap();
+        assert_eq!(run.wait_done(Duration::MAX), Some(RunState::Finished));
+        let elapsed = run.elapsed();
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(run.elapsed(), elapsed);
+        assert_eq!(run.state().code(), 4);
+        // 終了後の中止は理由に残さない
+        run.cancel();
+        assert_eq!(run.stop_reason(), None);
+        assert_eq!(RunState::Failed(AdError::Timeout("wait_run")).code(), 5);
+    }
+
+    #[test]
+    fn test_stop_conditions() {
+        let run = Run::with_conditions(StopConditions {
+            duration: Some(Duration::from_secs(2)),
+            sweeps: Some(10),
+            ..StopConditions::default()
+        });
+        let conditions = run.conditions();
+        assert_eq!(conditions.reached(&run, Duration::from_secs(1)), None);
+        assert_eq!(
+            conditions.reached(&run, Duration::from_secs(2)),
+            Some(StopReason::Duration)
+        );
+        run.set_sweeps(10);
+        assert_eq!(
+            conditions.reached(&run, Duration::from_secs(1)),
+            Some(StopReason::Sweeps)
+        );
+
+        // 最初に満たした条件が残る
+        run.stop(StopReason::Sweeps);
+        run.stop(StopReason::Duration);
+        assert_eq!(run.stop_reason(), Some(StopReason::Sweeps));
+        assert_eq!(run.state(), RunState::Stopping);
+    }
+
+    #[test]
+    fn test_stop_conditions_from_raw() {
+        let conditions = StopConditions::from_raw(0, 2_000_000, 0, 0, -1).unwrap();
+        assert_eq!(conditions.samples, Some(2_000_000));
+        assert_eq!(conditions.duration, None);
+        assert_eq!(conditions.dio_line, None);
+        assert_eq!(
+            StopConditions::from_raw(0, 0, 0, 0, 3).unwrap().dio_line,
+            Some(3)
+        );
+
+        assert!(StopConditions::from_raw(0, 0, 0, 0, -1).is_err());
+        assert!(StopConditions::from_raw(1, 0, 0, 0, 8).is_err());
+    }
+}
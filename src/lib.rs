@@ -5,15 +5,27 @@ mod operations;
 extern crate serde_derive;
 extern crate serde_json;
 
-use dotenv::dotenv;
+use helpers::config::RunConfig;
+use helpers::queue::{BlockQueue, BlockSender, QUEUE_CAPACITY};
+use helpers::run::{Run, RunState, StopConditions};
 use helpers::{helper, post};
-use operations::interface;
+use once_cell::sync::Lazy;
+use operations::trigger::TriggerLevel;
+use operations::{
+    backend, interface, AcquisitionStatus, AdBackend, AdError, Channels, Clock, Device, InputRange,
+    Trigger,
+};
 use std::cmp::Ordering;
-use std::os::raw::{c_int, c_short, c_uint};
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int, c_short, c_uchar, c_uint};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU32, Ordering as AtomicOrdering};
 use std::sync::{Arc, Mutex};
-use std::thread;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct RawDataset {
     x: i32,
     y: i32,
@@ -32,66 +44,630 @@ impl PartialOrd for RawDataset {
     }
 }
 
+/// 結果をドライバのエラーコードに変換する。0は正常終了
+fn to_error_code(result: Result<(), AdError>) -> c_short {
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("{}", e);
+            e.code()
+        }
+    }
+}
+
+/// Open device with specified ID
 #[no_mangle]
-pub extern "C" fn run(id: c_short, clk_time: c_int, seconds: u64) {
-    // sequence が走っているかを示すフラグ
-    // -1: not-started, 0: running, 1: finished
-    let flag = Arc::new(Mutex::new(0));
-    dotenv().ok();
+pub extern "C" fn open(id: c_short) -> c_short {
+    to_error_code(backend::shared().and_then(|backend| interface::open(&*backend, id)))
+}
 
-    // +/- 3.75μm駆動させたときに精度375nmで取るために必要な領域
-    const DATA_SIZE: usize = 20000;
+/// Close the connection with the device
+#[no_mangle]
+pub extern "C" fn close(id: c_short) -> c_short {
+    to_error_code(backend::shared().and_then(|backend| interface::close(&*backend, id)))
+}
 
-    let flg1 = Arc::clone(&flag);
-    let time_keeper = thread::spawn(move || {
-        helper::continuous_read(id, clk_time, seconds, flg1);
+/// 接続されているユニットのIDを`ids`に格納し、その数を`count`に格納する
+///
+/// # Safety
+///
+/// `ids` は書き込み可能な16個の`c_short`を、`count` は書き込み可能な1バイトを指していなければならない
+#[no_mangle]
+pub unsafe extern "C" fn list_devices(ids: *mut c_short, count: *mut c_uchar) -> c_short {
+    let result = backend::shared().map(|backend| {
+        let units = interface::attached_units(&*backend);
+        std::ptr::copy_nonoverlapping(units.as_ptr(), ids, units.len());
+        *count = units.len() as c_uchar;
     });
+    to_error_code(result)
+}
 
-    let flg2 = Arc::clone(&flag);
-    let data = Arc::new(Mutex::new(Vec::<RawDataset>::with_capacity(DATA_SIZE)));
+#[no_mangle]
+pub extern "C" fn set_clock(id: c_short, clock_time: c_int, sel: c_uchar) -> c_short {
+    let result =
+        backend::shared().and_then(|backend| interface::set_clock(&*backend, id, clock_time, sel));
+    to_error_code(result)
+}
 
-    let data_cln = Arc::clone(&data);
-    let job_runner = thread::spawn(move || {
-        helper::get_data(id, flg2, data_cln);
+/// サンプリング周波数を`rate` Hzに最も近い内部クロックに設定し、実際の周波数を`actual`に格納する
+/// 内部クロックの周期は20 nsの整数倍で、500倍(100 kHz)が上限
+///
+/// # Safety
+///
+/// `actual` は書き込み可能な`f64`を指していなければならない
+#[no_mangle]
+pub unsafe extern "C" fn set_sample_rate(id: c_short, rate: f64, actual: *mut f64) -> c_short {
+    let result = Clock::from_rate(rate).and_then(|clock| {
+        interface::set_clock(&*backend::shared()?, id, clock.clk_time(), clock.sel())?;
+        *actual = clock.rate();
+        Ok(())
+    });
+    to_error_code(result)
+}
+
+/// デジタル入力ポートの値を読み取り`data`に格納する
+///
+/// # Safety
+///
+/// `data` は書き込み可能な1バイトを指していなければならない
+#[no_mangle]
+pub unsafe extern "C" fn dio_read(id: c_short, data: *mut c_uchar) -> c_short {
+    let result = backend::shared()
+        .and_then(|backend| interface::dio_read(&*backend, id))
+        .map(|value| *data = value);
+    to_error_code(result)
+}
+
+/// デジタル出力ポートに`data`を出力する
+#[no_mangle]
+pub extern "C" fn dio_write(id: c_short, data: c_uchar) -> c_short {
+    to_error_code(backend::shared().and_then(|backend| interface::dio_write(&*backend, id, data)))
+}
+
+/// デジタル出力ポートに設定されている値を読み取り`data`に格納する
+///
+/// # Safety
+///
+/// `data` は書き込み可能な1バイトを指していなければならない
+#[no_mangle]
+pub unsafe extern "C" fn dio_check(id: c_short, data: *mut c_uchar) -> c_short {
+    let result = backend::shared()
+        .and_then(|backend| interface::dio_check(&*backend, id))
+        .map(|value| *data = value);
+    to_error_code(result)
+}
+
+/// `open` で開いた装置でCH1, CH2 を1回ずつ変換し、設定されているレンジで電圧に直して`data`に格納する
+/// 連続取り込み中は使用できない
+///
+/// # Safety
+///
+/// `data` は書き込み可能な2つの`f32`を指していなければならない
+#[no_mangle]
+pub unsafe extern "C" fn read_single(id: c_short, data: *mut f32) -> c_short {
+    let result = backend::shared()
+        .and_then(|backend| {
+            let values = interface::single_data(&*backend, id)?;
+            let (range1, range2) = interface::input_check(&*backend, id)?;
+            Ok([
+                range1.to_volts(values[0] as f32),
+                range2.to_volts(values[1] as f32),
+            ])
+        })
+        .map(|volts| std::ptr::copy_nonoverlapping(volts.as_ptr(), data, 2));
+    to_error_code(result)
+}
+
+/// 指定した秒数だけデータを取り込み、外部にpostする
+/// 返り値はドライバのエラーコードで、0なら正常終了
+#[no_mangle]
+pub extern "C" fn run(id: c_short, clk_time: c_int, seconds: u64) -> c_short {
+    let clock = Clock::Internal(clk_time);
+    to_error_code(run_sequence(RunConfig::new(
+        id,
+        clock,
+        StopConditions::duration(seconds),
+    )))
+}
+
+/// 終了の条件を指定して `run` を実行する。いずれかの条件を満たした時点で終える
+/// 0を指定した条件は使わない。条件が1つもなければエラーコード8を返す
+///
+/// * seconds - 取り込む秒数
+/// * samples - 取り込むサンプル数。ちょうどこの数で打ち切る
+/// * sweeps - ステージが端から端まで動いた回数
+/// * bin_count - 半分以上の位置で平均されたサンプル数
+/// * dio_line - このデジタル入力のビットがHighになったら終える。負の値なら使わない
+#[no_mangle]
+pub extern "C" fn run_until(
+    id: c_short,
+    clk_time: c_int,
+    seconds: u64,
+    samples: u64,
+    sweeps: u64,
+    bin_count: u64,
+    dio_line: c_short,
+) -> c_short {
+    let clock = Clock::Internal(clk_time);
+    let result = StopConditions::from_raw(seconds, samples, sweeps, bin_count, dio_line)
+        .and_then(|conditions| run_sequence(RunConfig::new(id, clock, conditions)));
+    to_error_code(result)
+}
+
+/// サンプリング周波数 `sample_rate` [Hz] を指定して `run` を実行する
+#[no_mangle]
+pub extern "C" fn run_at_rate(id: c_short, sample_rate: f64, seconds: u64) -> c_short {
+    let result = Clock::from_rate(sample_rate).and_then(|clock| {
+        run_sequence(RunConfig::new(id, clock, StopConditions::duration(seconds)))
+    });
+    to_error_code(result)
+}
+
+/// 外部クロックで `run` を実行する
+/// 装置はクロックの周波数を測れないので、`nominal_rate` [Hz] をサンプリング周波数として扱う
+#[no_mangle]
+pub extern "C" fn run_external_clock(id: c_short, nominal_rate: f64, seconds: u64) -> c_short {
+    let result = Clock::external(nominal_rate).and_then(|clock| {
+        run_sequence(RunConfig::new(id, clock, StopConditions::duration(seconds)))
+    });
+    to_error_code(result)
+}
+
+/// `ch` のチャネルだけで `run` を実行する
+/// 1チャネルの場合は位置ごとにまとめず、時系列としてpostする
+///
+/// * ch - 0: ch1のみ、1: ch2のみ、2: ch1, 2同時
+#[no_mangle]
+pub extern "C" fn run_channels(id: c_short, clk_time: c_int, seconds: u64, ch: c_uchar) -> c_short {
+    let clock = Clock::Internal(clk_time);
+    let result = Channels::from_code(ch).and_then(|channels| {
+        run_sequence(RunConfig {
+            channels,
+            ..RunConfig::new(id, clock, StopConditions::duration(seconds))
+        })
+    });
+    to_error_code(result)
+}
+
+/// `ids` の各装置で並列に `run` を実行する
+/// 返り値は最初に失敗した装置のエラーコードで、全て成功すれば0
+///
+/// # Safety
+///
+/// `ids` は読み込み可能な`count`個の`c_short`を指していなければならない
+#[no_mangle]
+pub unsafe extern "C" fn run_parallel(
+    ids: *const c_short,
+    count: c_uchar,
+    clk_time: c_int,
+    seconds: u64,
+) -> c_short {
+    let ids = std::slice::from_raw_parts(ids, count as usize);
+    to_error_code(run_devices(ids, Clock::Internal(clk_time), seconds))
+}
+
+/// トリガを指定して `run` を実行する
+///
+/// * trig_type - 0: ソフトウェアトリガ、1: 外部デジタル、2: アナログ立ち上がり、3: アナログ立下り
+/// * trig_ch - アナログトリガのチャネル、0: ch1, 1: ch2
+/// * level - アナログトリガの基準レベル, 1 ~ 65534
+/// * hysteresis - アナログトリガのノイズ除去レベル, 0 ~ 660
+/// * prelen - トリガより前に取り込んでおくサンプル数
+#[no_mangle]
+pub extern "C" fn run_with_trigger(
+    id: c_short,
+    clk_time: c_int,
+    seconds: u64,
+    trig_type: c_uchar,
+    trig_ch: c_uchar,
+    level: c_int,
+    hysteresis: c_short,
+    prelen: c_int,
+) -> c_short {
+    let level = TriggerLevel::Code(level);
+    let result = Trigger::from_raw(trig_type, trig_ch, level, hysteresis).and_then(|trigger| {
+        run_sequence(RunConfig {
+            trigger,
+            prelen,
+            ..RunConfig::new(
+                id,
+                Clock::Internal(clk_time),
+                StopConditions::duration(seconds),
+            )
+        })
+    });
+    to_error_code(result)
+}
+
+/// アナログトリガの基準レベルを電圧で指定して `run` を実行する
+/// 電圧はトリガチャネルの入力レンジで変換される
+#[no_mangle]
+pub extern "C" fn run_with_trigger_volts(
+    id: c_short,
+    clk_time: c_int,
+    seconds: u64,
+    trig_type: c_uchar,
+    trig_ch: c_uchar,
+    level: f32,
+    hysteresis: c_short,
+    prelen: c_int,
+) -> c_short {
+    let level = TriggerLevel::Volts(level);
+    let result = Trigger::from_raw(trig_type, trig_ch, level, hysteresis).and_then(|trigger| {
+        run_sequence(RunConfig {
+            trigger,
+            prelen,
+            ..RunConfig::new(
+                id,
+                Clock::Internal(clk_time),
+                StopConditions::duration(seconds),
+            )
+        })
+    });
+    to_error_code(result)
+}
+
+/// 設定ファイル `path` の設定で `run` を実行する
+/// 拡張子が `.json` ならJSON、それ以外はTOMLとして読む
+/// 設定に問題がある場合は全ての問題を表示し、取り込みを始めずにエラーコード8を返す
+///
+/// # Safety
+///
+/// `path` はヌル終端された文字列を指していなければならない
+#[no_mangle]
+pub unsafe extern "C" fn run_with_config(path: *const c_char) -> c_short {
+    let path = CStr::from_ptr(path).to_string_lossy();
+    let result = RunConfig::load(path.as_ref())
+        .map_err(|errors| {
+            print_config_errors(&errors);
+            AdError::InvalidParameters("run_with_config")
+        })
+        .and_then(run_sequence);
+    to_error_code(result)
+}
+
+fn print_config_errors(errors: &[String]) {
+    println!("Invalid config:");
+    for error in errors {
+        println!("  {}", error);
+    }
+}
+
+/// 実行中の計測。`abort_run` で中止できるように装置のIDごとに保持する
+static RUNS: Lazy<Mutex<HashMap<c_short, Arc<Run>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// `id` の装置で実行中の `run` などの計測を、指定した秒数を待たずに終了させる
+/// それまでに取り込んだデータはpostされ、中止された計測は正常終了として0を返す
+/// 実行中の計測がなければ何もしない
+#[no_mangle]
+pub extern "C" fn abort_run(id: c_short) -> c_short {
+    if let Some(run) = RUNS.lock().unwrap().get(&id) {
+        run.cancel();
+    }
+    0
+}
+
+/// 計測を行っているスレッド
+type Runner = JoinHandle<Result<(), AdError>>;
+
+/// `start_run` で開始した計測と、その計測を行っているスレッド
+type RunEntry = (Arc<Run>, Option<Runner>);
+
+/// `start_run` で開始した計測。`free_run` されるまでハンドルごとに保持する
+static HANDLES: Lazy<Mutex<HashMap<c_uint, RunEntry>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// 次に発行するハンドル。0は無効なハンドルとして使わない
+static NEXT_HANDLE: AtomicU32 = AtomicU32::new(1);
+
+/// `run_status` で返す計測の状況
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RunStatus {
+    /// 0: 開始前、1: 準備中、2: 取り込み中、3: 停止中、4: 正常終了、5: エラーで終了
+    state: c_uchar,
+    /// エラーで終了した場合のエラーコード。それ以外は0
+    error: c_short,
+    /// 計測を開始してからの秒数。終了後は終了までの秒数
+    elapsed: f64,
+    /// 取り込んだサンプル数
+    samples: u64,
+    /// データが入っている位置の数
+    bins: u64,
+    /// 取り込んだブロックのキューに溜まっている数
+    queue_depth: u64,
+    /// 処理が追いつかずに捨てたブロックの数
+    dropped_blocks: u64,
+    /// ステージが端から端まで動いた回数
+    sweeps: u64,
+    /// 計測を終えた理由。0: まだ終えていないかエラー、1: 時間、2: サンプル数、3: 往復の回数、
+    /// 4: 位置ごとのサンプル数、5: デジタル入力、6: 中止
+    stop_reason: c_uchar,
+}
+
+/// `run` と同じ計測を別のスレッドで開始し、終了を待たずにハンドルを`handle`に格納する
+/// 装置を開けなかった場合はそのエラーコードを返し、ハンドルは発行しない
+/// ハンドルは計測の終了後も `free_run` するまで有効
+///
+/// # Safety
+///
+/// `handle` は書き込み可能な`c_uint`を指していなければならない
+#[no_mangle]
+pub unsafe extern "C" fn start_run(
+    id: c_short,
+    clk_time: c_int,
+    seconds: u64,
+    handle: *mut c_uint,
+) -> c_short {
+    let clock = Clock::Internal(clk_time);
+    let result = spawn_sequence(RunConfig::new(id, clock, StopConditions::duration(seconds))).map(
+        |(run, runner)| {
+            let next = NEXT_HANDLE.fetch_add(1, AtomicOrdering::SeqCst);
+            HANDLES.lock().unwrap().insert(next, (run, Some(runner)));
+            *handle = next;
+        },
+    );
+    to_error_code(result)
+}
+
+/// `handle` の計測の状況を`status`に格納する
+///
+/// # Safety
+///
+/// `status` は書き込み可能な`RunStatus`を指していなければならない
+#[no_mangle]
+pub unsafe extern "C" fn run_status(handle: c_uint, status: *mut RunStatus) -> c_short {
+    let result = find_run(handle, "run_status").map(|run| {
+        let state = run.state();
+        *status = RunStatus {
+            state: state.code(),
+            error: match state {
+                RunState::Failed(e) => e.code(),
+                _ => 0,
+            },
+            elapsed: run.elapsed().as_secs_f64(),
+            samples: run.samples(),
+            bins: run.bins(),
+            queue_depth: run.queue_depth(),
+            dropped_blocks: run.dropped_blocks(),
+            sweeps: run.sweeps(),
+            stop_reason: run.stop_reason().map_or(0, |reason| reason.code()),
+        };
+    });
+    to_error_code(result)
+}
+
+/// `handle` の計測が終了するまで最大 `timeout_ms` ミリ秒待つ
+/// 正常終了なら0、エラーで終了した場合はそのエラーコード、時間内に終了しなければ98を返す
+#[no_mangle]
+pub extern "C" fn wait_run(handle: c_uint, timeout_ms: u64) -> c_short {
+    let result = find_run(handle, "wait_run").and_then(|run| {
+        match run.wait_done(Duration::from_millis(timeout_ms)) {
+            Some(RunState::Failed(e)) => Err(e),
+            Some(_) => Ok(()),
+            None => Err(AdError::Timeout("wait_run")),
+        }
+    });
+    to_error_code(result)
+}
+
+/// `handle` を解放する。計測が終了していなければ中止し、装置が閉じられるまで待つ
+/// 計測のスレッドがパニックしていた場合は103を返す
+#[no_mangle]
+pub extern "C" fn free_run(handle: c_uint) -> c_short {
+    let entry = HANDLES.lock().unwrap().remove(&handle);
+    let result = match entry {
+        Some((run, runner)) => {
+            run.cancel();
+            // 計測の結果は `wait_run` で受け取るので、ここでは捨てる
+            match runner.map(|runner| runner.join()) {
+                Some(Err(_)) => Err(AdError::Panicked("free_run")),
+                _ => Ok(()),
+            }
+        }
+        None => Err(AdError::InvalidId("free_run")),
+    };
+    to_error_code(result)
+}
+
+fn find_run(handle: c_uint, name: &'static str) -> Result<Arc<Run>, AdError> {
+    HANDLES
+        .lock()
+        .unwrap()
+        .get(&handle)
+        .map(|(run, _)| Arc::clone(run))
+        .ok_or(AdError::InvalidId(name))
+}
+
+/// 複数の装置で同時に取り込みを行う。各装置のデータは装置のIDを付けてpostされる
+fn run_devices(ids: &[c_short], clock: Clock, seconds: u64) -> Result<(), AdError> {
+    let conditions = StopConditions::duration(seconds);
+    let runners: Vec<_> = ids
+        .iter()
+        .map(|&id| thread::spawn(move || run_sequence(RunConfig::new(id, clock, conditions))))
+        .collect();
+
+    // 途中で失敗した装置があっても全ての取り込みを待つ
+    let results: Vec<_> = runners
+        .into_iter()
+        .map(|runner| {
+            runner
+                .join()
+                .unwrap_or(Err(AdError::Panicked("run_sequence")))
+        })
+        .collect();
+
+    results.into_iter().collect()
+}
+
+fn run_sequence(config: RunConfig) -> Result<(), AdError> {
+    let (_, runner) = spawn_sequence(config)?;
+    runner
+        .join()
+        .unwrap_or(Err(AdError::Panicked("run_sequence")))
+}
+
+/// 設定を確かめてから装置を開き、計測を別のスレッドで開始する
+/// 設定に問題がある場合や装置を開けなかった場合は計測を開始せずにエラーを返す
+fn spawn_sequence(config: RunConfig) -> Result<(Arc<Run>, Runner), AdError> {
+    if let Err(errors) = config.validate() {
+        print_config_errors(&errors);
+        return Err(AdError::InvalidParameters("RunConfig"));
+    }
+    let id = config.id;
+
+    // 全てのスレッドが終了した時点で装置は閉じられる
+    let device = Arc::new(Device::open(backend::shared()?, id)?);
+
+    let run = Arc::new(Run::with_conditions(config.stop));
+    let runner = spawn_runner(id, &run, move |run| run_threads(device, run, &config));
+    Ok((run, runner))
+}
+
+/// `run` を `id` の計測として登録し、`work` を別のスレッドで実行する
+/// `work` が終わると登録を外し、結果に応じて `run` を終了または失敗の状態にする
+/// `work` がパニックした場合も `Panicked` で失敗させ、待っている側が止まらないようにする
+fn spawn_runner<F>(id: c_short, run: &Arc<Run>, work: F) -> Runner
+where
+    F: FnOnce(&Arc<Run>) -> Result<(), AdError> + Send + 'static,
+{
+    RUNS.lock().unwrap().insert(id, Arc::clone(run));
+    let run = Arc::clone(run);
+    thread::spawn(move || {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| work(&run)))
+            .unwrap_or(Err(AdError::Panicked("run_threads")));
+        // 終了を待っている側が装置を再び開けるように、状態を進める前に登録を外す
+        RUNS.lock().unwrap().remove(&id);
+        match result {
+            Ok(()) => run.advance(RunState::Finished),
+            Err(e) => run.fail(e),
+        }
+        result
+    })
+}
+
+/// 終了したスレッドの結果を受け取る。パニックしていた場合は `Panicked` を返す
+fn joined<T>(result: thread::Result<T>, name: &'static str) -> Result<T, AdError> {
+    result.map_err(|_| {
+        println!("Paniced at {}", name);
+        AdError::Panicked(name)
+    })
+}
+
+/// 計測時間の管理、データの取り込み、データのまとめ、postをそれぞれのスレッドで行う
+/// 取り込んだブロックはキューでまとめるスレッドに渡し、取り込みが他の処理を待たないようにする
+/// `raw_data_path` が設定されていれば、生のデータもそのファイルに書き出す
+fn run_threads(
+    device: Arc<Device<dyn AdBackend>>,
+    run: &Arc<Run>,
+    config: &RunConfig,
+) -> Result<(), AdError> {
+    let channels = config.channels;
+
+    let run1 = Arc::clone(run);
+    let device1 = Arc::clone(&device);
+    let config1 = config.clone();
+    let time_keeper = thread::spawn(move || helper::continuous_read(&device1, &config1, &run1));
+
+    let prelen = config.prelen.max(0) as usize;
+    let binning = Arc::new(BlockQueue::new(QUEUE_CAPACITY));
+    let mut queues = vec![Arc::clone(&binning)];
+    let recorder = config.sinks.raw_data_path.clone().map(|path| {
+        let recording = Arc::new(BlockQueue::new(QUEUE_CAPACITY));
+        queues.push(Arc::clone(&recording));
+        thread::spawn(move || helper::record_blocks(&recording, &path))
     });
 
-    let data_cln2 = Arc::clone(&data);
-    let flg3 = Arc::clone(&flag);
-    let post_data = thread::spawn(move || {
-        let _ = post::post_data(id, flg3, data_cln2);
+    let run2 = Arc::clone(run);
+    let device2 = Arc::clone(&device);
+    // 後段の処理は全てこのサンプリング周波数を使う
+    let sample_rate = config.clock.rate();
+    let sender = BlockSender::new(queues);
+    let policy = config.polling;
+    let job_runner = thread::spawn(move || {
+        helper::get_data(
+            &device2,
+            channels,
+            prelen,
+            sample_rate,
+            &run2,
+            sender,
+            policy,
+        )
     });
 
-    time_keeper.join().expect("Paniced at time_keeper");
-    job_runner.join().expect("Paniced at job_runner");
-    post_data.join().expect("Paniced at post_data thread");
+    let data = Arc::new(Mutex::new(Vec::<RawDataset>::with_capacity(
+        config.capacity,
+    )));
+    let data_cln = Arc::clone(&data);
+    let binning_cln = Arc::clone(&binning);
+    let run3 = Arc::clone(run);
+    let filter = config.filter;
+    let binner =
+        thread::spawn(move || helper::bin_blocks(&binning_cln, channels, filter, &run3, data_cln));
+
+    let run4 = Arc::clone(run);
+    let binning_cln2 = Arc::clone(&binning);
+    let config4 = config.clone();
+    let post_data =
+        thread::spawn(move || post::post_data(&config4, sample_rate, &run4, &binning_cln2, data));
+
+    let read_result = time_keeper.join();
+    let data_result = job_runner.join();
+    let binner_result = binner.join();
+    let recorder_result = recorder.map(|recorder| recorder.join());
+    let post_result = post_data.join();
+    // 結果を返す前に装置を閉じておく
+    drop(device);
+
+    let read_result = joined(read_result, "time_keeper").and_then(|result| result);
+    let data_result = joined(data_result, "job_runner").and_then(|result| result);
+    let binner_result = joined(binner_result, "binner");
+    let recorder_result = recorder_result.map_or(Ok(()), |result| joined(result, "recorder"));
+    let post_result = joined(post_result, "post_data").and_then(|result| result);
+    if let Some(reason) = run.stop_reason() {
+        println!("Stopped by {:?}", reason);
+    }
+    println!(
+        "Block queue: max depth {}, {} block(s) dropped",
+        binning.max_depth(),
+        binning.dropped()
+    );
+
+    read_result
+        .and(data_result)
+        .and(binner_result)
+        .and(recorder_result)
+        .and(post_result)
 }
 
 #[no_mangle]
-pub extern "C" fn test_run() {
+pub extern "C" fn test_run() -> c_short {
+    to_error_code(test_sequence())
+}
+
+fn test_sequence() -> Result<(), AdError> {
     const MAX_LENGTH: usize = 100000;
-    let mut length: c_uint;
 
     let mut store: Vec<c_int> = vec![];
     let mut store2: Vec<c_int> = vec![];
 
-    interface::open(0);
-    interface::set_clock(0, 500, 0);
-    interface::input_set(0, 0, 0);
-    interface::start(0, 0, 0, 0, 0);
-    interface::trigger(0);
+    let device = Device::open(backend::shared()?, 0)?;
+    device.set_clock(500, 0)?;
+    device.input_set(InputRange::Bipolar10V, InputRange::Bipolar10V)?;
+    device.start(0, 0, 0, 0)?;
+    device.trigger()?;
 
     for _ in 0..20 {
         let mut data1 = [0 as c_int; MAX_LENGTH];
         let mut data2 = [0 as c_int; MAX_LENGTH];
-        let device_status = interface::status(true);
-        length = device_status.ch1_datalen;
+        let device_status = device.status(true)?;
+        let length = device_status.ch1_datalen.min(MAX_LENGTH as u32) as usize;
 
-        if device_status.status == 3 {
-            interface::takeout_data(0, 0, data1.as_mut_ptr(), &mut length as *mut u32);
-            interface::takeout_data(0, 1, data2.as_mut_ptr(), &mut length as *mut u32);
-        } else {
+        if device_status.status != AcquisitionStatus::Converting {
             continue;
         }
+        let length = device.takeout_data(0, &mut data1[..length])?;
+        let length = device.takeout_data(1, &mut data2[..length as usize])?;
 
         for i in 0..length as usize {
             store.push(data1[i]);
@@ -100,15 +676,58 @@ pub extern "C" fn test_run() {
         println!("length: {}", length);
     }
 
-    interface::stop(0);
-    interface::close(0);
+    device.stop()?;
+    drop(device);
 
     let mut a: Vec<f32> = vec![];
     let mut b: Vec<f32> = vec![];
-    for i in 0..store.len() as usize {
-        let result = post::convert_to_voltage(0, 0, store[i] as f32, store2[i] as f32);
+    for i in 0..store.len() {
+        let result = post::convert_to_voltage(
+            InputRange::Bipolar10V,
+            InputRange::Bipolar10V,
+            store[i] as f32,
+            store2[i] as f32,
+        );
         a.push(result.0);
         b.push(result.1);
     }
     helper::write_to_csv("C:/Users/yudai/Desktop/a.csv", &a, &b);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// `start_run` と同じようにハンドルに登録する
+    fn register(run: Arc<Run>, runner: Runner) -> c_uint {
+        let handle = NEXT_HANDLE.fetch_add(1, AtomicOrdering::SeqCst);
+        HANDLES.lock().unwrap().insert(handle, (run, Some(runner)));
+        handle
+    }
+
+    #[test]
+    fn test_worker_panic() {
+        let id = 99;
+
+        // 計測のスレッドが起動したスレッドがパニックした場合
+        let run = Arc::new(Run::default());
+        let runner = spawn_runner(id, &run, |_| {
+            let worker = thread::spawn(|| panic!("worker"));
+            joined(worker.join(), "worker")
+        });
+        let handle = register(Arc::clone(&run), runner);
+        assert_eq!(wait_run(handle, u64::MAX), 103);
+        assert_eq!(run.state(), RunState::Failed(AdError::Panicked("worker")));
+        assert!(!RUNS.lock().unwrap().contains_key(&id));
+        assert_eq!(free_run(handle), 0);
+
+        // 計測のスレッド自体がパニックした場合
+        let run = Arc::new(Run::default());
+        let runner = spawn_runner(id, &run, |_| panic!("runner"));
+        let handle = register(Arc::clone(&run), runner);
+        assert_eq!(wait_run(handle, u64::MAX), 103);
+        assert!(!RUNS.lock().unwrap().contains_key(&id));
+        assert_eq!(free_run(handle), 0);
+    }
 }
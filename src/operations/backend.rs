@@ -0,0 +1,217 @@
+use super::fault::parse_faults;
+use super::{
+    AdError, DriverBackend, RecordingBackend, ReplayBackend, Signal, SimulatedBackend, ThzPulse,
+};
+use dotenv::dotenv;
+use once_cell::sync::Lazy;
+use std::env;
+use std::os::raw::{c_int, c_short, c_uchar, c_uint};
+use std::sync::Arc;
+
+/// Operations provided by the TUSB16AD driver.
+///
+/// Each method corresponds to one function of the driver and returns its
+/// error code as is. Conversion to `AdError` is done in `interface`.
+#[allow(dead_code)]
+pub trait AdBackend: Send + Sync {
+    fn device_open(&self, id: c_short) -> c_short;
+    fn device_close(&self, id: c_short);
+    /// Read the digital input port
+    fn dio_in(&self, id: c_short, data: &mut c_uchar) -> c_short;
+    /// Write the digital output port
+    fn dio_out(&self, id: c_short, data: c_uchar) -> c_short;
+    /// Read back the value set to the digital output port
+    fn dio_chk(&self, id: c_short, data: &mut c_uchar) -> c_short;
+    /// Convert CH1 and CH2 once each
+    fn ad_single(&self, id: c_short, data: &mut [c_int; 2]) -> c_short;
+    fn start(
+        &self,
+        id: c_short,
+        ch: c_uchar,
+        prelen: c_int,
+        trig_type: c_uchar,
+        trig_ch: c_uchar,
+    ) -> c_short;
+    fn stop(&self, id: c_short) -> c_short;
+    fn ad_status(
+        &self,
+        id: c_short,
+        status: &mut c_uchar,
+        overflow: &mut [c_uchar; 2],
+        datalen: &mut [c_uint; 2],
+    ) -> c_short;
+    /// `datalen` is the requested length on entry and the stored length on return
+    fn ad_data(
+        &self,
+        id: c_short,
+        ch: c_uchar,
+        data: &mut [c_int],
+        datalen: &mut c_uint,
+    ) -> c_short;
+    fn adclk_set(&self, id: c_short, clk_time: c_int, sel: c_uchar) -> c_short;
+    fn level_set(&self, id: c_short, level: c_int, hys: c_short) -> c_short;
+    fn input_set(&self, id: c_short, type1: c_uchar, type2: c_uchar) -> c_short;
+    fn input_check(&self, id: c_short, type1: &mut c_uchar, type2: &mut c_uchar) -> c_short;
+    fn trigger(&self, id: c_short) -> c_short;
+}
+
+static SHARED: Lazy<Result<Arc<dyn AdBackend>, AdError>> = Lazy::new(|| {
+    dotenv().ok();
+    from_env()
+});
+
+/// Backend shared by the functions exported to C.
+/// It is selected once by `from_env` on the first call, and the error is
+/// returned to every call when the selection failed.
+pub fn shared() -> Result<Arc<dyn AdBackend>, AdError> {
+    SHARED.clone()
+}
+
+/// Select the backend with the environment variable `ADCONVERTER_BACKEND`.
+///
+/// * `driver` - TUSB16AD driver
+/// * `simulator` - simulated device
+/// * `replay` - recording at `ADCONVERTER_REPLAY` made with `ADCONVERTER_RECORD`
+///
+/// Without the variable, the driver is used in `release` builds and
+/// the simulator otherwise, also when the driver is not installed.
+/// When `driver` is set explicitly and the driver is not installed,
+/// `DriverNotInstalled` is returned. When the recording cannot be read,
+/// or any other name is set, `InvalidParameters` is returned.
+///
+/// When `ADCONVERTER_RECORD` is set, every call of the selected backend is
+/// recorded to the file at the path.
+pub fn from_env() -> Result<Arc<dyn AdBackend>, AdError> {
+    let backend = select()?;
+    Ok(match env::var("ADCONVERTER_RECORD") {
+        Ok(path) => match RecordingBackend::create(Arc::clone(&backend), &path) {
+            Ok(recording) => Arc::new(recording),
+            Err(e) => {
+                println!("Failed to create {}: {}, not recording", path, e);
+                backend
+            }
+        },
+        Err(_) => backend,
+    })
+}
+
+/// Backend named by `ADCONVERTER_BACKEND`, before wrapping it for the recording.
+///
+/// The units attached to the simulator are listed in `SIMULATOR_UNITS`,
+/// e.g. `0,1`. Only the unit 0 is attached by default.
+/// The signals fed to CH1 and CH2 are read from `SIMULATOR_CH1` and
+/// `SIMULATOR_CH2` (see `Signal::from_str`), and the rate of the external
+/// clock from `SIMULATOR_EXTERNAL_RATE` [Hz].
+/// With `SIMULATOR_THZ` (see `ThzPulse::from_str`), CH1 is the delay stage
+/// and CH2 the THz pulse at the stage position.
+/// `SIMULATOR_FAULTS` lists the faults occurring on all the units,
+/// e.g. `overflow@1,usb@2.5` (see `ScheduledFault::from_str`).
+fn select() -> Result<Arc<dyn AdBackend>, AdError> {
+    let name = env::var("ADCONVERTER_BACKEND").unwrap_or_default();
+    select_named(&name)
+}
+
+/// Backend named `name`, the default one when `name` is empty
+fn select_named(name: &str) -> Result<Arc<dyn AdBackend>, AdError> {
+    let use_driver = match name {
+        "driver" => return DriverBackend::load().map(|driver| Arc::new(driver) as _),
+        "simulator" => false,
+        "replay" => {
+            let path = env::var("ADCONVERTER_REPLAY").unwrap_or_default();
+            return match ReplayBackend::load(&path) {
+                Ok(replay) => Ok(Arc::new(replay)),
+                Err(e) => {
+                    println!("Failed to read '{}': {}", path, e);
+                    Err(AdError::InvalidParameters("ADCONVERTER_REPLAY"))
+                }
+            };
+        }
+        "" => cfg!(feature = "release"),
+        _ => {
+            println!("Unknown backend '{}'", name);
+            return Err(AdError::InvalidParameters("ADCONVERTER_BACKEND"));
+        }
+    };
+
+    if use_driver {
+        match DriverBackend::load() {
+            Ok(driver) => return Ok(Arc::new(driver)),
+            Err(e) => println!("{}, use the simulator", e),
+        }
+    }
+    let mut simulator = match env::var("SIMULATOR_UNITS") {
+        Ok(units) => SimulatedBackend::with_units(&parse_units(&units)),
+        Err(_) => SimulatedBackend::new(),
+    };
+    for (ch, key) in ["SIMULATOR_CH1", "SIMULATOR_CH2"].iter().enumerate() {
+        if let Ok(signal) = env::var(key) {
+            match signal.parse::<Signal>() {
+                Ok(signal) => simulator = simulator.with_signal(ch, signal),
+                Err(e) => println!("{}: {}, use the default signal", key, e),
+            }
+        }
+    }
+    if let Ok(pulse) = env::var("SIMULATOR_THZ") {
+        match pulse.parse::<ThzPulse>() {
+            Ok(pulse) => simulator = simulator.with_thz_pulse(pulse),
+            Err(e) => println!("SIMULATOR_THZ: {}, CH2 is not a THz pulse", e),
+        }
+    }
+    if let Ok(rate) = env::var("SIMULATOR_EXTERNAL_RATE") {
+        match rate.parse() {
+            Ok(rate) => simulator = simulator.with_external_rate(rate),
+            Err(_) => println!("Invalid SIMULATOR_EXTERNAL_RATE '{}'", rate),
+        }
+    }
+    if let Ok(faults) = env::var("SIMULATOR_FAULTS") {
+        match parse_faults(&faults) {
+            Ok(faults) => simulator = simulator.with_faults(&faults),
+            Err(e) => println!("SIMULATOR_FAULTS: {}, no fault is injected", e),
+        }
+    }
+    Ok(Arc::new(simulator))
+}
+
+/// Parse a comma separated list of unit IDs, ignoring invalid entries
+fn parse_units(units: &str) -> Vec<c_short> {
+    units
+        .split(',')
+        .filter_map(|id| id.trim().parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_units() {
+        assert_eq!(parse_units("0,1"), vec![0, 1]);
+        assert_eq!(parse_units(" 3 , x,"), vec![3]);
+    }
+
+    #[test]
+    fn test_unknown_backend() {
+        let error = select_named("simulater").err().unwrap();
+        assert_eq!(error, AdError::InvalidParameters("ADCONVERTER_BACKEND"));
+        assert!(select_named("simulator").is_ok());
+    }
+
+    #[test]
+    fn test_replay_missing() {
+        // The recording is not in the working directory
+        if env::var_os("ADCONVERTER_REPLAY").is_none() {
+            let error = select_named("replay").err().unwrap();
+            assert_eq!(error, AdError::InvalidParameters("ADCONVERTER_REPLAY"));
+        }
+    }
+
+    #[test]
+    fn test_driver_required() {
+        // The driver is not installed on the test machines
+        if env::var_os("TUSB16AD_PATH").is_none() {
+            let error = select_named("driver").err().unwrap();
+            assert_eq!(error, AdError::DriverNotInstalled("TUSB16AD"));
+        }
+    }
+}
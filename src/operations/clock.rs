@@ -0,0 +1,113 @@
+use super::AdError;
+use std::os::raw::{c_int, c_uchar};
+use std::time::Duration;
+
+/// Frequency of the internal clock ticks (20 ns)
+const TICK_FREQUENCY: f64 = 50e6;
+/// Smallest `ClkTime` accepted by `TUSB0216AD_AdClk_Set`
+pub const MIN_CLK_TIME: c_int = 500;
+
+/// Sampling clock of the continuous sampling
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Clock {
+    /// Internal clock whose period is `ClkTime` * 20 ns
+    Internal(c_int),
+    /// External clock. The converter cannot measure its rate,
+    /// so the rate declared by the user is used downstream.
+    External { nominal_rate: f64 },
+}
+
+impl Clock {
+    /// Internal clock closest to `rate` Hz.
+    /// The achieved rate is given by `Clock::rate`.
+    pub fn from_rate(rate: f64) -> Result<Self, AdError> {
+        let clk_time = (TICK_FREQUENCY / rate).round();
+        if !(MIN_CLK_TIME as f64..=c_int::MAX as f64).contains(&clk_time) {
+            return Err(AdError::InvalidParameters("TUSB0216AD_AdClk_Set"));
+        }
+        Ok(Clock::Internal(clk_time as c_int))
+    }
+
+    /// External clock running at `nominal_rate` Hz
+    pub fn external(nominal_rate: f64) -> Result<Self, AdError> {
+        if !(nominal_rate > 0.0 && nominal_rate.is_finite()) {
+            return Err(AdError::InvalidParameters("TUSB0216AD_AdClk_Set"));
+        }
+        Ok(Clock::External { nominal_rate })
+    }
+
+    /// Sampling rate in Hz
+    pub fn rate(&self) -> f64 {
+        match self {
+            Clock::Internal(clk_time) => TICK_FREQUENCY / *clk_time as f64,
+            Clock::External { nominal_rate } => *nominal_rate,
+        }
+    }
+
+    /// `ClkTime` of `TUSB0216AD_AdClk_Set`.
+    /// The driver ignores it for the external clock but still validates it.
+    pub fn clk_time(&self) -> c_int {
+        match self {
+            Clock::Internal(clk_time) => *clk_time,
+            Clock::External { .. } => MIN_CLK_TIME,
+        }
+    }
+
+    /// `sel` of `TUSB0216AD_AdClk_Set`
+    pub fn sel(&self) -> c_uchar {
+        match self {
+            Clock::Internal(_) => 0,
+            Clock::External { .. } => 1,
+        }
+    }
+}
+
+/// Time to convert `samples` at `rate` Hz.
+/// Returns `None` when it is too long to be represented or the rate is not positive.
+pub fn sampling_time(samples: u64, rate: f64) -> Option<Duration> {
+    if samples == 0 {
+        return Some(Duration::from_secs(0));
+    }
+    let seconds = samples as f64 / rate;
+    if seconds > 0.0 && seconds < u64::MAX as f64 {
+        Some(Duration::from_secs_f64(seconds))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_clock_from_rate() {
+        let clock = Clock::from_rate(100e3).unwrap();
+        assert_eq!(clock, Clock::Internal(500));
+        assert_eq!(clock.rate(), 100e3);
+
+        // 20 ns の整数倍に丸められる
+        let clock = Clock::from_rate(30e3).unwrap();
+        assert_eq!(clock.clk_time(), 1667);
+        assert!((clock.rate() - 29994.0).abs() < 1.0);
+
+        assert!(Clock::from_rate(101e3).is_err());
+        assert!(Clock::from_rate(0.0).is_err());
+    }
+
+    #[test]
+    fn test_external_clock() {
+        let clock = Clock::external(10e3).unwrap();
+        assert_eq!(clock.sel(), 1);
+        assert_eq!(clock.rate(), 10e3);
+        assert!(Clock::external(-1.0).is_err());
+    }
+
+    #[test]
+    fn test_sampling_time() {
+        assert_eq!(sampling_time(1000, 100e3), Some(Duration::from_millis(10)));
+        assert_eq!(sampling_time(0, 0.0), Some(Duration::from_secs(0)));
+        assert_eq!(sampling_time(1000, 0.0), None);
+        assert_eq!(sampling_time(u64::MAX, 1e-9), None);
+    }
+}
@@ -0,0 +1,201 @@
+use super::interface;
+use super::trigger::{Trigger, TriggerLevel};
+use super::{AdBackend, AdError, Clock, DeviceStatus, InputRange};
+use std::os::raw::{c_int, c_short, c_uchar, c_uint};
+use std::sync::Arc;
+
+/// Handle of an opened TUSB-0216ADMZ unit.
+///
+/// The unit is opened in `Device::open` and closed when the handle is dropped,
+/// so it is released even if the thread using it panics.
+pub struct Device<B: AdBackend + ?Sized> {
+    backend: Arc<B>,
+    id: c_short,
+}
+
+impl<B: AdBackend + ?Sized> Device<B> {
+    /// Open the device whose unit switch is set to `id`
+    pub fn open(backend: Arc<B>, id: c_short) -> Result<Self, AdError> {
+        interface::open(&*backend, id)?;
+        Ok(Device { backend, id })
+    }
+
+    pub fn set_clock(&self, clock_time: c_int, sel: c_uchar) -> Result<(), AdError> {
+        interface::set_clock(&*self.backend, self.id, clock_time, sel)
+    }
+
+    /// Set the sampling clock and return the sampling rate in Hz
+    pub fn configure_clock(&self, clock: &Clock) -> Result<f64, AdError> {
+        self.set_clock(clock.clk_time(), clock.sel())?;
+        Ok(clock.rate())
+    }
+
+    /// Set the input ranges of CH1 and CH2, verifying that the device accepted them
+    pub fn input_set(&self, range1: InputRange, range2: InputRange) -> Result<(), AdError> {
+        interface::input_set(&*self.backend, self.id, range1, range2)
+    }
+
+    pub fn input_check(&self) -> Result<(InputRange, InputRange), AdError> {
+        interface::input_check(&*self.backend, self.id)
+    }
+
+    /// Read the digital input port
+    pub fn dio_read(&self) -> Result<c_uchar, AdError> {
+        interface::dio_read(&*self.backend, self.id)
+    }
+
+    /// Write `data` to the digital output port
+    pub fn dio_write(&self, data: c_uchar) -> Result<(), AdError> {
+        interface::dio_write(&*self.backend, self.id, data)
+    }
+
+    /// Read back the value set to the digital output port
+    pub fn dio_check(&self) -> Result<c_uchar, AdError> {
+        interface::dio_check(&*self.backend, self.id)
+    }
+
+    /// Convert CH1 and CH2 once each and return the voltages
+    /// in the input ranges currently set.
+    /// Not available during continuous sampling.
+    pub fn read_single(&self) -> Result<[f32; 2], AdError> {
+        let data = interface::single_data(&*self.backend, self.id)?;
+        let (range1, range2) = self.input_check()?;
+
+        Ok([
+            range1.to_volts(data[0] as f32),
+            range2.to_volts(data[1] as f32),
+        ])
+    }
+
+    pub fn start(
+        &self,
+        ch: c_uchar,
+        prelen: c_int,
+        trig_type: c_uchar,
+        trig_ch: c_uchar,
+    ) -> Result<(), AdError> {
+        interface::start(&*self.backend, self.id, ch, prelen, trig_type, trig_ch)
+    }
+
+    pub fn level_set(&self, level: c_int, hys: c_short) -> Result<(), AdError> {
+        interface::level_set(&*self.backend, self.id, level, hys)
+    }
+
+    /// Start the continuous sampling armed with `trigger`.
+    /// The level of an analog trigger is set before starting.
+    /// The software trigger still has to be fired with `trigger`.
+    pub fn start_triggered(
+        &self,
+        ch: c_uchar,
+        prelen: c_int,
+        trigger: &Trigger,
+    ) -> Result<(), AdError> {
+        if let Trigger::Analog {
+            ch: trig_ch,
+            level,
+            hysteresis,
+            ..
+        } = *trigger
+        {
+            let level = match level {
+                TriggerLevel::Code(code) => code,
+                TriggerLevel::Volts(volts) => {
+                    let ranges = self.input_check()?;
+                    let range = if trig_ch == 0 { ranges.0 } else { ranges.1 };
+                    range.to_code(volts)
+                }
+            };
+            self.level_set(level, hysteresis)?;
+        }
+        self.start(ch, prelen, trigger.trig_type(), trigger.trig_ch())
+    }
+
+    pub fn stop(&self) -> Result<(), AdError> {
+        interface::stop(&*self.backend, self.id)
+    }
+
+    pub fn trigger(&self) -> Result<(), AdError> {
+        interface::trigger(&*self.backend, self.id)
+    }
+
+    pub fn status(&self, verbose: bool) -> Result<DeviceStatus, AdError> {
+        interface::status(&*self.backend, self.id, verbose)
+    }
+
+    pub fn takeout_data(&self, ch: c_uchar, data: &mut [c_int]) -> Result<c_uint, AdError> {
+        interface::takeout_data(&*self.backend, self.id, ch, data)
+    }
+}
+
+impl<B: AdBackend + ?Sized> Drop for Device<B> {
+    fn drop(&mut self) {
+        // A sampling left running would keep the unit busy after closing
+        let _ = interface::stop(&*self.backend, self.id);
+        if let Err(e) = interface::close(&*self.backend, self.id) {
+            println!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::operations::trigger::Edge;
+    use crate::operations::SimulatedBackend;
+
+    #[test]
+    fn test_open_device() {
+        let backend = Arc::new(SimulatedBackend::new());
+        let device = Device::open(Arc::clone(&backend), 0).unwrap();
+        assert_eq!(
+            device.input_check().unwrap(),
+            (InputRange::Bipolar10V, InputRange::Bipolar10V)
+        );
+
+        assert_eq!(
+            Device::open(backend, 1).err().unwrap(),
+            AdError::OpenFailed("TUSB0216AD_Device_Open")
+        );
+    }
+
+    #[test]
+    fn test_configure_clock() {
+        let device = Device::open(Arc::new(SimulatedBackend::new()), 0).unwrap();
+        let clock = Clock::from_rate(50e3).unwrap();
+        assert_eq!(device.configure_clock(&clock), Ok(50e3));
+
+        let clock = Clock::external(12.5e3).unwrap();
+        assert_eq!(device.configure_clock(&clock), Ok(12.5e3));
+    }
+
+    #[test]
+    fn test_read_single() {
+        let device = Device::open(Arc::new(SimulatedBackend::new()), 0).unwrap();
+        let volts = device.read_single().unwrap();
+        assert!(volts.iter().all(|v| (-10.0..=10.0).contains(v)));
+    }
+
+    #[test]
+    fn test_start_triggered() {
+        let device = Device::open(Arc::new(SimulatedBackend::new()), 0).unwrap();
+        let trigger = Trigger::Analog {
+            ch: 0,
+            edge: Edge::Rising,
+            level: TriggerLevel::Volts(1.0),
+            hysteresis: 100,
+        };
+        device.start_triggered(2, 0, &trigger).unwrap();
+
+        // +/-10 V のレンジでは表現できない電圧
+        let trigger = Trigger::Analog {
+            ch: 1,
+            edge: Edge::Falling,
+            level: TriggerLevel::Volts(12.0),
+            hysteresis: 100,
+        };
+        assert_eq!(
+            device.start_triggered(2, 0, &trigger),
+            Err(AdError::InvalidParameters("TUSB0216AD_Level_Set"))
+        );
+    }
+}
@@ -0,0 +1,320 @@
+use super::{AdBackend, AdError};
+use libloading::Library;
+use std::env;
+use std::ffi::OsStr;
+use std::os::raw::{c_int, c_short, c_uchar, c_uint};
+
+/// Backend calling the TUSB16AD driver.
+///
+/// The driver is loaded at runtime, so the library also works on machines
+/// without the driver as long as the simulator is used.
+pub struct DriverBackend {
+    // TUSB16ADのドライバに定義されいるMicrosoft Visual Cインターフェース群
+    device_open: unsafe extern "C" fn(id: c_short) -> c_short,
+    device_close: unsafe extern "C" fn(id: c_short),
+    /// 指定IDのデバイスのデジタル入力ポートの入力値を読み取りDataに格納する
+    dio_in: unsafe extern "C" fn(id: c_short, data: *mut c_uchar) -> c_short,
+    /// 指定IDのデバイスのデジタル入力ポートの出力値を読み取りDataに格納する
+    dio_out: unsafe extern "C" fn(id: c_short, data: c_uchar) -> c_short,
+    /// 指定IDのデバイスのデジタル入力ポートの出力値を確認する
+    dio_chk: unsafe extern "C" fn(id: c_short, data: *mut c_uchar) -> c_short,
+    /// 指定IDのデバイスのアナログ入力電圧をデジタル変換して取得
+    /// channel1, 2を１回ずつ変換
+    /// 連続測定時には使用不可
+    ad_single: unsafe extern "C" fn(id: c_short, data: *mut c_int) -> c_short,
+    /// 連続測定を開始
+    /// ch: 0 (1chのみ)、1(2chのみ)、2(1, 2ch同時)
+    /// PreLen: プレトリガ長
+    /// TrigType: トリガ種類、0: 内部トリガ、1: 外部デジタル、2: アナログ立ち上がり、3: アナログ立下り
+    /// TrigCh: アナログトリガのトリガチャネル、0: ch1, 1: ch2
+    start: unsafe extern "C" fn(
+        id: c_short,
+        ch: c_uchar,
+        pre_len: c_int,
+        trig_type: c_uchar,
+        trig_ch: c_uchar,
+    ) -> c_short,
+    /// 連続取り込み停止
+    stop: unsafe extern "C" fn(id: c_short) -> c_short,
+    /// 連続取り込みの状態確認
+    /// status: 0 or 2: 停止中、1: トリガ待ち、3: トリガ後変換中
+    /// overflow: overflow状態, [ch1, ch2]という構造、0: overflowなし, 1: overflow
+    /// datalen: 取り込み済みデータ数、[ch1, ch2]という構造
+    ad_status: unsafe extern "C" fn(
+        id: c_short,
+        status: *mut c_uchar,
+        overflow: *mut c_uchar,
+        datalen: *mut c_uint,
+    ) -> c_short,
+    /// 取り込み済みデータを取得
+    /// data: 取り込みデータの格納先のポインタ
+    /// datalen: 取り込み要求長。1~262144、戻るときには実際に取得された数が入っている
+    ad_data: unsafe extern "C" fn(
+        id: c_short,
+        ch: c_uchar,
+        data: *mut c_int,
+        datalen: *mut c_uint,
+    ) -> c_short,
+    /// クロック時間の設定
+    /// ClkTime: 内部クロック周期設定、500 ~ 2147483647。クロック周期 = ClkTime * 20 ns
+    /// sel: クロックソース、0: 内部クロック、1: 外部クロック
+    adclk_set: unsafe extern "C" fn(id: c_short, clk_time: c_int, sel: c_uchar) -> c_short,
+    /// 連続サンプリング時のアナログトリガ基準レベルの設定
+    /// level: アナログ立ち上がり、下がり時の基準トリガ, 1 ~ 65534
+    /// hys: ノイズ除去レベル。0 ~ 660でノイズより十分大きく信号振幅より小さな値
+    level_set: unsafe extern "C" fn(id: c_short, level: c_int, hys: c_short) -> c_short,
+    /// 入力レンジの設定
+    /// type1: ch1のレンジ設定
+    /// type2: ch2のレンジ設定
+    /// 0: +/-10V, 1: +/-5V, 2: +/-2.5V, 3: +/-1.25V, 4: 10V, 5: 5V, 6: 2.5V
+    input_set: unsafe extern "C" fn(id: c_short, type1: c_uchar, type2: c_uchar) -> c_short,
+    /// 入力レンジの確認
+    /// type1, type2 にはそれぞれのチャネルでのレンジの番号が入る
+    /// 返り値はエラーコード
+    input_check:
+        unsafe extern "C" fn(id: c_short, type1: *mut c_uchar, type2: *mut c_uchar) -> c_short,
+    /// ソフトウェアトリガを掛ける
+    trigger: unsafe extern "C" fn(id: c_short) -> c_short,
+    // Keeps the functions above valid
+    _library: Library,
+}
+
+/// Look up a function of the driver
+///
+/// # Safety
+///
+/// `T` must be the function pointer type matching the symbol `name`
+unsafe fn symbol<T: Copy>(library: &Library, name: &'static str) -> Result<T, AdError> {
+    library
+        .get::<T>(name.as_bytes())
+        .map(|symbol| *symbol)
+        .map_err(|_| AdError::DriverNotInstalled(name))
+}
+
+impl DriverBackend {
+    /// Load the TUSB16AD driver.
+    /// The path of the library can be overridden by the environment variable
+    /// `TUSB16AD_PATH`, e.g. to use a stub library in tests.
+    pub fn load() -> Result<Self, AdError> {
+        match env::var_os("TUSB16AD_PATH") {
+            Some(path) => Self::load_from(path),
+            None => Self::load_from(libloading::library_filename("TUSB16AD")),
+        }
+    }
+
+    /// Load the driver from the library at `path`
+    pub fn load_from<P: AsRef<OsStr>>(path: P) -> Result<Self, AdError> {
+        unsafe {
+            let library =
+                Library::new(path).map_err(|_| AdError::DriverNotInstalled("TUSB16AD"))?;
+
+            Ok(DriverBackend {
+                device_open: symbol(&library, "TUSB0216AD_Device_Open")?,
+                device_close: symbol(&library, "TUSB0216AD_Device_Close")?,
+                dio_in: symbol(&library, "TUSB0216AD_DIO_In")?,
+                dio_out: symbol(&library, "TUSB0216AD_DIO_Out")?,
+                dio_chk: symbol(&library, "TUSB0216AD_DIO_Chk")?,
+                ad_single: symbol(&library, "TUSB0216AD_Ad_Single")?,
+                start: symbol(&library, "TUSB0216AD_Start")?,
+                stop: symbol(&library, "TUSB0216AD_Stop")?,
+                ad_status: symbol(&library, "TUSB0216AD_Ad_Status")?,
+                ad_data: symbol(&library, "TUSB0216AD_Ad_Data")?,
+                adclk_set: symbol(&library, "TUSB0216AD_AdClk_Set")?,
+                level_set: symbol(&library, "TUSB0216AD_Level_Set")?,
+                input_set: symbol(&library, "TUSB0216AD_Input_Set")?,
+                input_check: symbol(&library, "TUSB0216AD_Input_Check")?,
+                trigger: symbol(&library, "TUSB0216AD_Trigger")?,
+                _library: library,
+            })
+        }
+    }
+}
+
+impl AdBackend for DriverBackend {
+    fn device_open(&self, id: c_short) -> c_short {
+        unsafe { (self.device_open)(id) }
+    }
+
+    fn device_close(&self, id: c_short) {
+        unsafe { (self.device_close)(id) }
+    }
+
+    fn dio_in(&self, id: c_short, data: &mut c_uchar) -> c_short {
+        unsafe { (self.dio_in)(id, data as *mut c_uchar) }
+    }
+
+    fn dio_out(&self, id: c_short, data: c_uchar) -> c_short {
+        unsafe { (self.dio_out)(id, data) }
+    }
+
+    fn dio_chk(&self, id: c_short, data: &mut c_uchar) -> c_short {
+        unsafe { (self.dio_chk)(id, data as *mut c_uchar) }
+    }
+
+    fn ad_single(&self, id: c_short, data: &mut [c_int; 2]) -> c_short {
+        unsafe { (self.ad_single)(id, data.as_mut_ptr()) }
+    }
+
+    fn start(
+        &self,
+        id: c_short,
+        ch: c_uchar,
+        prelen: c_int,
+        trig_type: c_uchar,
+        trig_ch: c_uchar,
+    ) -> c_short {
+        unsafe { (self.start)(id, ch, prelen, trig_type, trig_ch) }
+    }
+
+    fn stop(&self, id: c_short) -> c_short {
+        unsafe { (self.stop)(id) }
+    }
+
+    fn ad_status(
+        &self,
+        id: c_short,
+        status: &mut c_uchar,
+        overflow: &mut [c_uchar; 2],
+        datalen: &mut [c_uint; 2],
+    ) -> c_short {
+        unsafe {
+            (self.ad_status)(
+                id,
+                status as *mut c_uchar,
+                overflow.as_mut_ptr(),
+                datalen.as_mut_ptr(),
+            )
+        }
+    }
+
+    fn ad_data(
+        &self,
+        id: c_short,
+        ch: c_uchar,
+        data: &mut [c_int],
+        datalen: &mut c_uint,
+    ) -> c_short {
+        // The driver must not write beyond the buffer
+        *datalen = (*datalen).min(data.len() as c_uint);
+        unsafe { (self.ad_data)(id, ch, data.as_mut_ptr(), datalen as *mut c_uint) }
+    }
+
+    fn adclk_set(&self, id: c_short, clk_time: c_int, sel: c_uchar) -> c_short {
+        unsafe { (self.adclk_set)(id, clk_time, sel) }
+    }
+
+    fn level_set(&self, id: c_short, level: c_int, hys: c_short) -> c_short {
+        unsafe { (self.level_set)(id, level, hys) }
+    }
+
+    fn input_set(&self, id: c_short, type1: c_uchar, type2: c_uchar) -> c_short {
+        unsafe { (self.input_set)(id, type1, type2) }
+    }
+
+    fn input_check(&self, id: c_short, type1: &mut c_uchar, type2: &mut c_uchar) -> c_short {
+        unsafe { (self.input_check)(id, type1 as *mut c_uchar, type2 as *mut c_uchar) }
+    }
+
+    fn trigger(&self, id: c_short) -> c_short {
+        unsafe { (self.trigger)(id) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::operations::{AcquisitionStatus, Device, InputRange};
+    use std::path::PathBuf;
+    use std::process::Command;
+    use std::sync::Arc;
+
+    /// Build the stub driver in `tusb16ad-stub` and return the path of the library
+    fn stub_library() -> PathBuf {
+        let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let target_dir = root.join("target").join("tusb16ad-stub");
+        let status = Command::new(env!("CARGO"))
+            .arg("build")
+            .arg("--quiet")
+            .arg("--manifest-path")
+            .arg(root.join("tusb16ad-stub").join("Cargo.toml"))
+            .arg("--target-dir")
+            .arg(&target_dir)
+            .status()
+            .expect("Failed to run cargo");
+        assert!(status.success(), "Failed to build the stub driver");
+
+        target_dir
+            .join("debug")
+            .join(libloading::library_filename("tusb16ad_stub"))
+    }
+
+    #[test]
+    fn test_stub_driver() {
+        let driver = Arc::new(DriverBackend::load_from(stub_library()).unwrap());
+        assert_eq!(
+            Device::open(Arc::clone(&driver), 1).err(),
+            Some(AdError::OpenFailed("TUSB0216AD_Device_Open"))
+        );
+
+        let device = Device::open(Arc::clone(&driver), 0).unwrap();
+        assert_eq!(
+            Device::open(Arc::clone(&driver), 0).err(),
+            Some(AdError::AlreadyOpened("TUSB0216AD_Device_Open"))
+        );
+
+        device
+            .input_set(InputRange::Bipolar10V, InputRange::Unipolar10V)
+            .unwrap();
+        assert_eq!(
+            device.input_check().unwrap(),
+            (InputRange::Bipolar10V, InputRange::Unipolar10V)
+        );
+        assert_eq!(device.read_single().unwrap(), [-10.0, 10.0]);
+
+        assert_eq!(device.dio_read().unwrap(), 0b1010_0101);
+        device.dio_write(0x0f).unwrap();
+        assert_eq!(device.dio_check().unwrap(), 0x0f);
+
+        assert_eq!(
+            device.set_clock(499, 0),
+            Err(AdError::InvalidParameters("TUSB0216AD_AdClk_Set"))
+        );
+        device.set_clock(500, 0).unwrap();
+        device.start(2, 0, 0, 0).unwrap();
+        device.trigger().unwrap();
+        let status = device.status(false).unwrap();
+        assert_eq!(status.status, AcquisitionStatus::Converting);
+        assert_eq!((status.ch1_datalen, status.ch2_datalen), (1000, 1000));
+
+        // The length passed to the driver is limited by the buffer
+        let mut data = [0; 10];
+        assert_eq!(device.takeout_data(0, &mut data).unwrap(), 10);
+        assert_eq!(data, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(device.takeout_data(1, &mut data).unwrap(), 10);
+        assert_eq!(data[0], 65535);
+        assert_eq!(
+            device.read_single(),
+            Err(AdError::SequentialReading("TUSB0216AD_Ad_Single"))
+        );
+
+        // Closing on drop makes the unit available again
+        drop(device);
+        drop(Device::open(driver, 0).unwrap());
+    }
+
+    #[test]
+    fn test_driver_not_installed() {
+        let error = DriverBackend::load_from("/nonexistent/TUSB16AD")
+            .err()
+            .unwrap();
+        assert_eq!(error, AdError::DriverNotInstalled("TUSB16AD"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_missing_symbol() {
+        // libc can be loaded but does not provide the driver functions
+        let error = DriverBackend::load_from("libc.so.6").err().unwrap();
+        assert_eq!(error, AdError::DriverNotInstalled("TUSB0216AD_Device_Open"));
+    }
+}
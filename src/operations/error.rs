@@ -0,0 +1,146 @@
+use std::error::Error;
+use std::fmt;
+use std::os::raw::c_short;
+
+/// Errors reported by the TUSB0216AD driver.
+/// Every variant keeps the name of the driver function that failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdError {
+    /// 1: Invalid ID
+    InvalidId(&'static str),
+    /// 2: Invalid driver
+    InvalidDriver(&'static str),
+    /// 3: Device already opened
+    AlreadyOpened(&'static str),
+    /// 4: Too many devices
+    TooManyDevices(&'static str),
+    /// 5: Failed to open device
+    OpenFailed(&'static str),
+    /// 6: Device not found
+    DeviceNotFound(&'static str),
+    /// 8: Parameters are invalid
+    InvalidParameters(&'static str),
+    /// 9: USB connection error
+    UsbError(&'static str),
+    /// 11: Sequential reading
+    SequentialReading(&'static str),
+    /// 99 or any undocumented code
+    Other(&'static str, c_short),
+    /// The driver library or one of its functions could not be loaded.
+    /// Reported to C callers as code 2.
+    DriverNotInstalled(&'static str),
+    /// The device did not accept the input range, or reported an unknown one.
+    /// Reported to C callers as code 8.
+    RangeNotAccepted(&'static str),
+    /// The continuous sampling stopped before the run finished.
+    /// Reported to C callers as code 102.
+    DeviceStopped(&'static str),
+    /// The buffer of the device overflowed and the run was aborted.
+    /// Reported to C callers as code 101.
+    BufferOverflow(&'static str),
+    /// The run did not finish within the timeout.
+    /// Reported to C callers as code 98.
+    Timeout(&'static str),
+    /// The stage did not get ready within the timeout.
+    /// Reported to C callers as code 100.
+    StageNotReady(&'static str),
+    /// A thread of the run panicked.
+    /// Reported to C callers as code 103.
+    Panicked(&'static str),
+}
+
+impl AdError {
+    /// Convert an error code returned by the driver.
+    /// Returns `None` when the code means success.
+    pub fn from_code(code: c_short, func_name: &'static str) -> Option<Self> {
+        match code {
+            0 => None,
+            1 => Some(AdError::InvalidId(func_name)),
+            2 => Some(AdError::InvalidDriver(func_name)),
+            3 => Some(AdError::AlreadyOpened(func_name)),
+            4 => Some(AdError::TooManyDevices(func_name)),
+            5 => Some(AdError::OpenFailed(func_name)),
+            6 => Some(AdError::DeviceNotFound(func_name)),
+            8 => Some(AdError::InvalidParameters(func_name)),
+            9 => Some(AdError::UsbError(func_name)),
+            11 => Some(AdError::SequentialReading(func_name)),
+            _ => Some(AdError::Other(func_name, code)),
+        }
+    }
+
+    /// Error code as documented in the driver manual
+    pub fn code(&self) -> c_short {
+        match self {
+            AdError::InvalidId(_) => 1,
+            AdError::InvalidDriver(_) => 2,
+            AdError::AlreadyOpened(_) => 3,
+            AdError::TooManyDevices(_) => 4,
+            AdError::OpenFailed(_) => 5,
+            AdError::DeviceNotFound(_) => 6,
+            AdError::InvalidParameters(_) => 8,
+            AdError::UsbError(_) => 9,
+            AdError::SequentialReading(_) => 11,
+            AdError::Other(_, code) => *code,
+            AdError::DriverNotInstalled(_) => 2,
+            AdError::RangeNotAccepted(_) => 8,
+            AdError::DeviceStopped(_) => 102,
+            AdError::BufferOverflow(_) => 101,
+            AdError::Timeout(_) => 98,
+            AdError::StageNotReady(_) => 100,
+            AdError::Panicked(_) => 103,
+        }
+    }
+
+    /// Name of the driver function that failed
+    pub fn func_name(&self) -> &'static str {
+        match self {
+            AdError::InvalidId(name)
+            | AdError::InvalidDriver(name)
+            | AdError::AlreadyOpened(name)
+            | AdError::TooManyDevices(name)
+            | AdError::OpenFailed(name)
+            | AdError::DeviceNotFound(name)
+            | AdError::InvalidParameters(name)
+            | AdError::UsbError(name)
+            | AdError::SequentialReading(name)
+            | AdError::Other(name, _)
+            | AdError::DriverNotInstalled(name)
+            | AdError::RangeNotAccepted(name)
+            | AdError::DeviceStopped(name)
+            | AdError::BufferOverflow(name)
+            | AdError::Timeout(name)
+            | AdError::StageNotReady(name)
+            | AdError::Panicked(name) => name,
+        }
+    }
+}
+
+impl fmt::Display for AdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            AdError::InvalidId(_) => "Invalid ID",
+            AdError::InvalidDriver(_) => "Invalid Driver",
+            AdError::AlreadyOpened(_) => "Device already opened",
+            AdError::TooManyDevices(_) => "Too many devices",
+            AdError::OpenFailed(_) => "Failed to open device",
+            AdError::DeviceNotFound(_) => "Device not found",
+            AdError::InvalidParameters(_) => "Parameters are invalid",
+            AdError::UsbError(_) => "USB connection error",
+            AdError::SequentialReading(_) => "Sequential reading",
+            AdError::Other(_, _) => "Other error",
+            AdError::DriverNotInstalled(_) => "Driver not installed",
+            AdError::RangeNotAccepted(_) => "Input range not accepted",
+            AdError::DeviceStopped(_) => "Sampling stopped unexpectedly",
+            AdError::BufferOverflow(_) => "Buffer overflow",
+            AdError::Timeout(_) => "Timed out",
+            AdError::StageNotReady(_) => "Stage not ready",
+            AdError::Panicked(_) => "Thread panicked",
+        };
+        match self {
+            AdError::Other(name, code) => write!(f, "{}: {} ({})", name, message, code),
+            _ => write!(f, "{}: {}", self.func_name(), message),
+        }
+    }
+}
+
+impl Error for AdError {}
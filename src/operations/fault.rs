@@ -0,0 +1,101 @@
+use std::os::raw::c_uint;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Failure injected into a simulated unit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Every call fails with the USB connection error (code 9)
+    UsbError,
+    /// The unit is unplugged and every call fails with code 6
+    Disconnected,
+    /// The samples waiting in the memory are lost and the overflow flags are set
+    Overflow,
+    /// `TUSB0216AD_Ad_Data` returns at most this many samples per call
+    ShortData(c_uint),
+    /// The conversion stops, but `TUSB0216AD_Ad_Status` keeps reporting it
+    StalledStatus,
+}
+
+/// Fault occurring some time after the unit is opened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScheduledFault {
+    pub fault: Fault,
+    pub after: Duration,
+}
+
+/// Parse a fault written as `name@seconds`, e.g. `usb@2.5`.
+/// The names are `usb`, `disconnect`, `overflow`, `short=<samples>` and `stall`.
+/// Without `@seconds`, the fault occurs from the beginning.
+impl FromStr for ScheduledFault {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, seconds) = match s.trim().split_once('@') {
+            Some((name, seconds)) => (name, seconds),
+            None => (s.trim(), "0"),
+        };
+        let after = seconds
+            .parse()
+            .ok()
+            .filter(|seconds: &f64| *seconds >= 0.0 && seconds.is_finite())
+            .map(Duration::from_secs_f64)
+            .ok_or_else(|| format!("Invalid time of fault: '{}'", seconds))?;
+
+        let fault = match name.split_once('=') {
+            Some(("short", length)) => Fault::ShortData(
+                length
+                    .parse()
+                    .map_err(|_| format!("Invalid length of short data: '{}'", length))?,
+            ),
+            Some(_) => return Err(format!("Unknown fault '{}'", name)),
+            None => match name {
+                "usb" => Fault::UsbError,
+                "disconnect" => Fault::Disconnected,
+                "overflow" => Fault::Overflow,
+                "stall" => Fault::StalledStatus,
+                _ => return Err(format!("Unknown fault '{}'", name)),
+            },
+        };
+        Ok(ScheduledFault { fault, after })
+    }
+}
+
+/// Parse a comma separated list of faults
+pub fn parse_faults(faults: &str) -> Result<Vec<ScheduledFault>, String> {
+    faults
+        .split(',')
+        .filter(|fault| !fault.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_faults() {
+        assert_eq!(
+            parse_faults("usb@2.5, short=100, stall@1").unwrap(),
+            vec![
+                ScheduledFault {
+                    fault: Fault::UsbError,
+                    after: Duration::from_millis(2500),
+                },
+                ScheduledFault {
+                    fault: Fault::ShortData(100),
+                    after: Duration::from_secs(0),
+                },
+                ScheduledFault {
+                    fault: Fault::StalledStatus,
+                    after: Duration::from_secs(1),
+                },
+            ]
+        );
+        assert!(parse_faults("").unwrap().is_empty());
+        assert!(parse_faults("usb@-1").is_err());
+        assert!(parse_faults("fire@1").is_err());
+        assert!(parse_faults("short=x").is_err());
+    }
+}
@@ -2,270 +2,199 @@ use super::utils;
 use super::*;
 use std::os::raw::{c_int, c_short, c_uchar, c_uint};
 
-#[cfg(not(feature = "release"))]
-use rand::Rng;
-#[cfg(not(feature = "release"))]
-use std::f64::consts::PI;
-
 /// Open device with specified ID
-#[no_mangle]
-pub extern "C" fn open(id: c_short) {
-    #[cfg(feature = "release")]
-    {
-        let error: c_short;
-        unsafe {
-            error = TUSB0216AD_Device_Open(id);
-        }
-        utils::parse_error(error, "TUSB0216AD_Device_Open");
-    }
-
-    #[cfg(not(feature = "release"))]
-    {
-        let error: c_short;
-        match id {
-            0 => error = 0,
-            _ => error = 5,
-        }
-        utils::parse_error(error, "TUSB0216AD_Device_Open");
-    }
+pub fn open<B: AdBackend + ?Sized>(backend: &B, id: c_short) -> Result<(), AdError> {
+    let error = backend.device_open(id);
+    utils::parse_error(error, "TUSB0216AD_Device_Open")
 }
 
 /// Close the connection with the device
-#[no_mangle]
-pub extern "C" fn close(_id: c_short) {
-    #[cfg(feature = "release")]
-    {
-        unsafe {
-            TUSB0216AD_Device_Close(_id);
-        }
-    }
+pub fn close<B: AdBackend + ?Sized>(backend: &B, id: c_short) -> Result<(), AdError> {
+    backend.device_close(id);
+    Ok(())
 }
 
-#[allow(dead_code)]
-pub fn single_data(_id: c_short, _data: *mut c_int) {
-    #[cfg(feature = "release")]
-    {
-        let error: c_short;
-        unsafe {
-            error = TUSB0216AD_Ad_Single(_id, _data);
-        }
+/// Unit switch numbers that can be set on a TUSB-0216ADMZ
+pub const UNIT_IDS: std::ops::RangeInclusive<c_short> = 0..=15;
 
-        utils::parse_error(error, "TUSB0216AD_Ad_Single");
-    }
+/// List the IDs of the attached units.
+/// Each unit is opened and closed again, so units opened elsewhere
+/// are listed without being closed.
+pub fn attached_units<B: AdBackend + ?Sized>(backend: &B) -> Vec<c_short> {
+    UNIT_IDS
+        .filter(|id| match open(backend, *id) {
+            Ok(()) => {
+                backend.device_close(*id);
+                true
+            }
+            Err(AdError::AlreadyOpened(_)) => true,
+            Err(_) => false,
+        })
+        .collect()
 }
 
-pub fn start(id: c_short, ch: c_uchar, prelen: c_int, trig_type: c_uchar, trig_ch: c_uchar) {
-    let error: c_short;
+/// Read the digital input port
+pub fn dio_read<B: AdBackend + ?Sized>(backend: &B, id: c_short) -> Result<c_uchar, AdError> {
+    let mut data: c_uchar = 0;
+    let error = backend.dio_in(id, &mut data);
+    utils::parse_error(error, "TUSB0216AD_DIO_In")?;
 
-    #[cfg(feature = "release")]
-    {
-        unsafe {
-            error = TUSB0216AD_Start(id, ch, prelen, trig_type, trig_ch);
-        }
-    }
-    #[cfg(not(feature = "release"))]
-    {
-        if id != 0 || ch > 2 || prelen < 0 || trig_type > 3 || trig_ch > 1 {
-            error = 5;
-        } else {
-            error = 0;
-        }
-    }
-    utils::parse_error(error, "TUSB0216AD_Start");
+    Ok(data)
 }
 
-pub fn stop(id: c_short) {
-    let error: c_short;
-    #[cfg(feature = "release")]
-    {
-        unsafe {
-            error = TUSB0216AD_Stop(id);
-        }
-    }
-    #[cfg(not(feature = "release"))]
-    {
-        if id != 0 {
-            error = 5;
-        } else {
-            error = 0;
-        }
-    }
-    utils::parse_error(error, "TUSB0216AD_Stop");
+/// Write `data` to the digital output port
+pub fn dio_write<B: AdBackend + ?Sized>(
+    backend: &B,
+    id: c_short,
+    data: c_uchar,
+) -> Result<(), AdError> {
+    let error = backend.dio_out(id, data);
+    utils::parse_error(error, "TUSB0216AD_DIO_Out")
+}
+
+/// Read back the value currently set to the digital output port
+pub fn dio_check<B: AdBackend + ?Sized>(backend: &B, id: c_short) -> Result<c_uchar, AdError> {
+    let mut data: c_uchar = 0;
+    let error = backend.dio_chk(id, &mut data);
+    utils::parse_error(error, "TUSB0216AD_DIO_Chk")?;
+
+    Ok(data)
+}
+
+/// Convert CH1 and CH2 once each.
+/// Not available during continuous sampling.
+pub fn single_data<B: AdBackend + ?Sized>(backend: &B, id: c_short) -> Result<[c_int; 2], AdError> {
+    let mut data: [c_int; 2] = [0, 0];
+    let error = backend.ad_single(id, &mut data);
+    utils::parse_error(error, "TUSB0216AD_Ad_Single")?;
+
+    Ok(data)
+}
+
+pub fn start<B: AdBackend + ?Sized>(
+    backend: &B,
+    id: c_short,
+    ch: c_uchar,
+    prelen: c_int,
+    trig_type: c_uchar,
+    trig_ch: c_uchar,
+) -> Result<(), AdError> {
+    let error = backend.start(id, ch, prelen, trig_type, trig_ch);
+    utils::parse_error(error, "TUSB0216AD_Start")
+}
+
+pub fn stop<B: AdBackend + ?Sized>(backend: &B, id: c_short) -> Result<(), AdError> {
+    let error = backend.stop(id);
+    utils::parse_error(error, "TUSB0216AD_Stop")
 }
 
 /// Show the device status
 ///
 /// * verbose: bool
-///     if true, it prints the status on the screen
-pub fn status(verbose: bool) -> DeviceStatus {
+///   if true, it prints the status on the screen
+pub fn status<B: AdBackend + ?Sized>(
+    backend: &B,
+    id: c_short,
+    verbose: bool,
+) -> Result<DeviceStatus, AdError> {
     let mut status: u8 = 1;
     let mut overflow: [u8; 2] = [0, 0];
     let mut datalen: [u32; 2] = [0, 0];
-    #[cfg(feature = "release")]
-    {
-        unsafe {
-            TUSB0216AD_Ad_Status(
-                0,
-                &mut status as *mut u8,
-                overflow.as_mut_ptr(),
-                datalen.as_mut_ptr(),
-            );
-        }
-    }
 
-    #[cfg(not(feature = "release"))]
-    {
-        status = 3;
-        overflow = [0, 0];
-        datalen = [10000, 10000];
-    }
+    let error = backend.ad_status(id, &mut status, &mut overflow, &mut datalen);
+    utils::parse_error(error, "TUSB0216AD_Ad_Status")?;
 
-    match verbose {
-        true => {
-            println!("============");
-            println!("Status: {}", status);
-            println!("Overflow: {:?}", overflow);
-            println!("DataLen: {:?}", datalen);
-            println!("============");
-        }
-        false => {}
+    if verbose {
+        println!("============");
+        println!("Status: {}", status);
+        println!("Overflow: {:?}", overflow);
+        println!("DataLen: {:?}", datalen);
+        println!("============");
     }
 
-    DeviceStatus::new(status, datalen[0], datalen[1])
+    Ok(DeviceStatus::new(status, overflow, datalen[0], datalen[1]))
 }
 
-pub fn takeout_data(id: c_short, ch: c_uchar, data: *mut c_int, length: *mut c_uint) {
-    let mut error: c_short;
+/// Take out the converted data of the channel `ch` into `data`.
+/// The length of `data` is the requested length (1 ~ 262144).
+///
+/// Returns the number of samples actually stored
+pub fn takeout_data<B: AdBackend + ?Sized>(
+    backend: &B,
+    id: c_short,
+    ch: c_uchar,
+    data: &mut [c_int],
+) -> Result<c_uint, AdError> {
+    let mut length = data.len() as c_uint;
+    let error = backend.ad_data(id, ch, data, &mut length);
+    utils::parse_error(error, "TUSB0216AD_Ad_Data")?;
 
-    #[cfg(feature = "release")]
-    {
-        unsafe {
-            error = TUSB0216AD_Ad_Data(id, ch, data, length);
-        }
-    }
-    #[cfg(not(feature = "release"))]
-    {
-        let mut rng = rand::thread_rng();
-
-        if id != 0 {
-            error = 5;
-            utils::parse_error(error, "TUSB0216AD_Ad_Data");
-        }
-        unsafe {
-            if ch != 0 && ch != 1 {
-                error = 8;
-            } else {
-                *length = 100000;
-                let height = 2f32.powf(15.0);
-
-                for i in 0..100000 {
-                    let phase = 2e-4 * 2.0 * PI as f32 * i as f32;
-                    let random: f32 = rng.gen();
-                    let noise = 1e3 * random;
-
-                    *data.offset(i) = (height * (phase.sin() + 1.0) + noise) as i32;
-                }
-                error = 0;
-            }
-        }
-    }
-    utils::parse_error(error, "TUSB0216AD_Ad_Data");
+    Ok(length)
 }
 
-#[no_mangle]
-pub extern "C" fn set_clock(id: c_short, clock_time: c_int, sel: c_uchar) {
-    let mut error: c_short;
-    #[cfg(feature = "release")]
-    {
-        unsafe {
-            error = TUSB0216AD_AdClk_Set(id, clock_time, sel);
-        }
-    }
-    #[cfg(not(feature = "release"))]
-    {
-        error = 0;
-        if id != 0 {
-            error = 5;
-        }
-
-        if clock_time < 500 {
-            error = 8;
-        }
-
-        if sel != 0 && sel != 1 {
-            error = 8;
-        }
-    }
-    utils::parse_error(error, "TUSB0216AD_AdClk_Set");
+pub fn set_clock<B: AdBackend + ?Sized>(
+    backend: &B,
+    id: c_short,
+    clock_time: c_int,
+    sel: c_uchar,
+) -> Result<(), AdError> {
+    let error = backend.adclk_set(id, clock_time, sel);
+    utils::parse_error(error, "TUSB0216AD_AdClk_Set")
+}
+
+/// Set the reference level (1 ~ 65534) and the hysteresis (0 ~ 660)
+/// of the analog trigger
+pub fn level_set<B: AdBackend + ?Sized>(
+    backend: &B,
+    id: c_short,
+    level: c_int,
+    hys: c_short,
+) -> Result<(), AdError> {
+    let error = backend.level_set(id, level, hys);
+    utils::parse_error(error, "TUSB0216AD_Level_Set")
 }
 
 /// Change input range of each channel.
-/// Specify the input ranges with a number.
-/// 0: +/-10 V, 1: +/-5V, 2: +/-2.5 V, 3: +/-1.25V
-/// 4: 10 V, 5: 5 V, 6: 2.5 V
+/// The ranges are read back and `RangeNotAccepted` is returned
+/// when the device did not accept them.
 ///
 /// * `id` - Device number
-/// * `type1` - input range of CH1
-/// * `type2` - input range of CH2
-pub fn input_set(id: c_short, type1: c_uchar, type2: c_uchar) {
-    let error: c_short;
-    #[cfg(feature = "release")]
-    {
-        unsafe {
-            error = TUSB0216AD_Input_Set(id, type1, type2);
-        }
-    }
-    #[cfg(not(feature = "release"))]
-    {
-        if id != 0 || type1 > 6 || type2 > 6 {
-            error = 5;
-        } else {
-            error = 0;
-        }
+/// * `range1` - input range of CH1
+/// * `range2` - input range of CH2
+pub fn input_set<B: AdBackend + ?Sized>(
+    backend: &B,
+    id: c_short,
+    range1: InputRange,
+    range2: InputRange,
+) -> Result<(), AdError> {
+    let error = backend.input_set(id, range1.code(), range2.code());
+    utils::parse_error(error, "TUSB0216AD_Input_Set")?;
+
+    if input_check(backend, id)? != (range1, range2) {
+        return Err(AdError::RangeNotAccepted("TUSB0216AD_Input_Set"));
     }
-    utils::parse_error(error, "TUSB0216AD_Input_Set");
+    Ok(())
 }
 
-pub fn input_check(id: c_short, type1: *mut c_uchar, type2: *mut c_uchar) {
-    let error: c_short;
-    #[cfg(feature = "release")]
-    {
-        unsafe {
-            error = TUSB0216AD_Input_Check(id, type1, type2);
-        }
-    }
-    #[cfg(not(feature = "release"))]
-    {
-        unsafe {
-            if id != 0 {
-                error = 5;
-            } else {
-                error = 0;
-            }
-            *type1 = 0;
-            *type2 = 0;
-        }
-    }
-    utils::parse_error(error, "TUSB0216AD_Input_Check");
+/// Read the input ranges of CH1 and CH2
+pub fn input_check<B: AdBackend + ?Sized>(
+    backend: &B,
+    id: c_short,
+) -> Result<(InputRange, InputRange), AdError> {
+    let mut type1: c_uchar = 0;
+    let mut type2: c_uchar = 0;
+    let error = backend.input_check(id, &mut type1, &mut type2);
+    utils::parse_error(error, "TUSB0216AD_Input_Check")?;
+
+    Ok((
+        InputRange::from_code(type1, "TUSB0216AD_Input_Check")?,
+        InputRange::from_code(type2, "TUSB0216AD_Input_Check")?,
+    ))
 }
 
-pub fn trigger(id: c_short) {
-    let error: c_short;
-    #[cfg(feature = "release")]
-    {
-        unsafe {
-            error = TUSB0216AD_Trigger(id);
-        }
-    }
-    #[cfg(not(feature = "release"))]
-    {
-        if id != 0 {
-            error = 5;
-        } else {
-            error = 0;
-        }
-    }
-    utils::parse_error(error, "TUSB0216AD_Trigger");
+pub fn trigger<B: AdBackend + ?Sized>(backend: &B, id: c_short) -> Result<(), AdError> {
+    let error = backend.trigger(id);
+    utils::parse_error(error, "TUSB0216AD_Trigger")
 }
 
 #[cfg(test)]
@@ -275,13 +204,197 @@ mod test {
     #[test]
     fn test_ad_data_mock() {
         const MAX_LENGTH: usize = 100000;
-        let mut length = MAX_LENGTH as u32;
+        let backend = SimulatedBackend::new();
+        open(&backend, 0).unwrap();
         let mut data1 = [0; MAX_LENGTH];
         let mut data2 = [0; MAX_LENGTH];
-        let l_ptr = &mut length as *mut u32;
-        takeout_data(0, 0, data1.as_mut_ptr(), l_ptr);
-        takeout_data(0, 1, data2.as_mut_ptr(), l_ptr);
 
-        assert_eq!(length, 100000);
+        // 計測を始めるまでデータはない
+        assert_eq!(takeout_data(&backend, 0, 0, &mut data1), Ok(0));
+
+        // 100 kHz でトリガ前の2000点が溜まってからトリガが掛かる
+        start(&backend, 0, 2, 2000, 1, 0).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(30));
+        stop(&backend, 0).unwrap();
+        let length = takeout_data(&backend, 0, 0, &mut data1).unwrap();
+        assert!(length >= 2000);
+
+        let length = takeout_data(&backend, 0, 1, &mut data2).unwrap();
+        assert!(length >= 2000);
+    }
+
+    #[test]
+    fn test_error_mock() {
+        let backend = SimulatedBackend::new();
+        open(&backend, 0).unwrap();
+        assert_eq!(
+            open(&backend, 1),
+            Err(AdError::OpenFailed("TUSB0216AD_Device_Open"))
+        );
+        assert_eq!(
+            set_clock(&backend, 0, 499, 0),
+            Err(AdError::InvalidParameters("TUSB0216AD_AdClk_Set"))
+        );
+
+        let mut data = [0; 10];
+        assert_eq!(
+            takeout_data(&backend, 0, 2, &mut data),
+            Err(AdError::InvalidParameters("TUSB0216AD_Ad_Data"))
+        );
+    }
+
+    #[test]
+    fn test_single_data_mock() {
+        let backend = SimulatedBackend::new();
+        open(&backend, 0).unwrap();
+        let data = single_data(&backend, 0).unwrap();
+        assert!(data.iter().all(|code| (0..65536).contains(code)));
+
+        assert_eq!(
+            single_data(&backend, 1),
+            Err(AdError::OpenFailed("TUSB0216AD_Ad_Single"))
+        );
+    }
+
+    #[test]
+    fn test_overflow_mock() {
+        let backend = SimulatedBackend::new();
+        open(&backend, 0).unwrap();
+        assert_eq!(status(&backend, 0, false).unwrap().overflow, [false, false]);
+
+        backend.set_overflow(0, [0, 1]);
+        let device_status = status(&backend, 0, false).unwrap();
+        assert_eq!(device_status.overflow, [false, true]);
+    }
+
+    #[test]
+    fn test_input_set_mock() {
+        let backend = SimulatedBackend::new();
+        open(&backend, 0).unwrap();
+        input_set(&backend, 0, InputRange::Bipolar5V, InputRange::Unipolar2_5V).unwrap();
+        assert_eq!(
+            input_check(&backend, 0),
+            Ok((InputRange::Bipolar5V, InputRange::Unipolar2_5V))
+        );
+
+        // 装置がレンジを受け付けなかった場合
+        backend.lock_ranges(0);
+        assert_eq!(
+            input_set(&backend, 0, InputRange::Bipolar10V, InputRange::Bipolar10V),
+            Err(AdError::RangeNotAccepted("TUSB0216AD_Input_Set"))
+        );
+    }
+
+    #[test]
+    fn test_acquisition_status() {
+        assert_eq!(AcquisitionStatus::from_code(0), AcquisitionStatus::Stopped);
+        assert_eq!(AcquisitionStatus::from_code(2), AcquisitionStatus::Stopped);
+        assert_eq!(
+            AcquisitionStatus::from_code(1),
+            AcquisitionStatus::WaitingForTrigger
+        );
+
+        let backend = SimulatedBackend::new();
+        open(&backend, 0).unwrap();
+        assert_eq!(
+            status(&backend, 0, false).unwrap().status,
+            AcquisitionStatus::Stopped
+        );
+        start(&backend, 0, 2, 0, 0, 0).unwrap();
+        assert_eq!(
+            status(&backend, 0, false).unwrap().status,
+            AcquisitionStatus::WaitingForTrigger
+        );
+        trigger(&backend, 0).unwrap();
+        assert_eq!(
+            status(&backend, 0, false).unwrap().status,
+            AcquisitionStatus::Converting
+        );
+    }
+
+    #[test]
+    fn test_attached_units() {
+        assert_eq!(attached_units(&SimulatedBackend::new()), vec![0]);
+
+        let backend = SimulatedBackend::with_units(&[0, 1]);
+        assert_eq!(attached_units(&backend), vec![0, 1]);
+
+        // 各ユニットの状態は独立している
+        open(&backend, 0).unwrap();
+        open(&backend, 1).unwrap();
+        dio_write(&backend, 1, 0b0011).unwrap();
+        assert_eq!(dio_check(&backend, 0), Ok(0));
+        assert_eq!(dio_check(&backend, 1), Ok(0b0011));
+        assert!(status(&backend, 1, false).is_ok());
+        assert_eq!(
+            status(&backend, 2, false).err().unwrap(),
+            AdError::OpenFailed("TUSB0216AD_Ad_Status")
+        );
+
+        // 開いているユニットも接続されている
+        assert_eq!(attached_units(&backend), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_call_ordering() {
+        let backend = SimulatedBackend::new();
+        assert_eq!(
+            start(&backend, 0, 2, 0, 0, 0),
+            Err(AdError::OpenFailed("TUSB0216AD_Start"))
+        );
+
+        open(&backend, 0).unwrap();
+        assert_eq!(
+            open(&backend, 0),
+            Err(AdError::AlreadyOpened("TUSB0216AD_Device_Open"))
+        );
+
+        // 連続取り込み中は単発の変換や設定の変更ができない
+        start(&backend, 0, 2, 0, 0, 0).unwrap();
+        assert_eq!(
+            single_data(&backend, 0),
+            Err(AdError::SequentialReading("TUSB0216AD_Ad_Single"))
+        );
+        assert_eq!(
+            set_clock(&backend, 0, 500, 0),
+            Err(AdError::SequentialReading("TUSB0216AD_AdClk_Set"))
+        );
+        assert_eq!(
+            start(&backend, 0, 2, 0, 0, 0),
+            Err(AdError::SequentialReading("TUSB0216AD_Start"))
+        );
+        stop(&backend, 0).unwrap();
+        assert!(single_data(&backend, 0).is_ok());
+
+        // 閉じると取り込みも止まる
+        start(&backend, 0, 2, 0, 1, 0).unwrap();
+        close(&backend, 0).unwrap();
+        assert_eq!(
+            takeout_data(&backend, 0, 0, &mut [0; 10]),
+            Err(AdError::OpenFailed("TUSB0216AD_Ad_Data"))
+        );
+        open(&backend, 0).unwrap();
+        assert_eq!(
+            status(&backend, 0, false).unwrap().status,
+            AcquisitionStatus::Stopped
+        );
+    }
+
+    #[test]
+    fn test_dio_mock() {
+        let backend = SimulatedBackend::new();
+        open(&backend, 0).unwrap();
+        assert_eq!(dio_check(&backend, 0), Ok(0));
+
+        dio_write(&backend, 0, 0b0101).unwrap();
+        assert_eq!(dio_check(&backend, 0), Ok(0b0101));
+
+        backend.set_dio_input(0, 0b0010);
+        assert_eq!(dio_read(&backend, 0), Ok(0b0010));
+
+        assert_eq!(
+            dio_write(&backend, 1, 0),
+            Err(AdError::OpenFailed("TUSB0216AD_DIO_Out"))
+        );
     }
 }
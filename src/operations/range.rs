@@ -0,0 +1,151 @@
+use super::AdError;
+use std::os::raw::{c_int, c_uchar};
+use std::str::FromStr;
+
+/// Largest straight binary code of the 16-bit converter
+const MAX_CODE: f32 = 65535.0;
+
+/// Input range of a channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputRange {
+    /// +/-10 V
+    Bipolar10V,
+    /// +/-5 V
+    Bipolar5V,
+    /// +/-2.5 V
+    Bipolar2_5V,
+    /// +/-1.25 V
+    Bipolar1_25V,
+    /// 0 ~ 10 V
+    Unipolar10V,
+    /// 0 ~ 5 V
+    Unipolar5V,
+    /// 0 ~ 2.5 V
+    Unipolar2_5V,
+}
+
+impl InputRange {
+    /// Convert the range number used by `TUSB0216AD_Input_Set` and
+    /// `TUSB0216AD_Input_Check`. `func_name` is reported for unknown numbers.
+    pub fn from_code(code: c_uchar, func_name: &'static str) -> Result<Self, AdError> {
+        match code {
+            0 => Ok(InputRange::Bipolar10V),
+            1 => Ok(InputRange::Bipolar5V),
+            2 => Ok(InputRange::Bipolar2_5V),
+            3 => Ok(InputRange::Bipolar1_25V),
+            4 => Ok(InputRange::Unipolar10V),
+            5 => Ok(InputRange::Unipolar5V),
+            6 => Ok(InputRange::Unipolar2_5V),
+            _ => Err(AdError::RangeNotAccepted(func_name)),
+        }
+    }
+
+    /// Range number used by the driver
+    pub fn code(self) -> c_uchar {
+        match self {
+            InputRange::Bipolar10V => 0,
+            InputRange::Bipolar5V => 1,
+            InputRange::Bipolar2_5V => 2,
+            InputRange::Bipolar1_25V => 3,
+            InputRange::Unipolar10V => 4,
+            InputRange::Unipolar5V => 5,
+            InputRange::Unipolar2_5V => 6,
+        }
+    }
+
+    /// Lowest voltage of the range
+    pub fn min_volts(self) -> f32 {
+        match self {
+            InputRange::Bipolar10V => -10.0,
+            InputRange::Bipolar5V => -5.0,
+            InputRange::Bipolar2_5V => -2.5,
+            InputRange::Bipolar1_25V => -1.25,
+            _ => 0.0,
+        }
+    }
+
+    /// Highest voltage of the range
+    pub fn max_volts(self) -> f32 {
+        match self {
+            InputRange::Bipolar10V | InputRange::Unipolar10V => 10.0,
+            InputRange::Bipolar5V | InputRange::Unipolar5V => 5.0,
+            InputRange::Bipolar2_5V | InputRange::Unipolar2_5V => 2.5,
+            InputRange::Bipolar1_25V => 1.25,
+        }
+    }
+
+    pub fn width(self) -> f32 {
+        self.max_volts() - self.min_volts()
+    }
+
+    /// Convert a straight binary value to a voltage.
+    /// `code` may be an average of several samples.
+    pub fn to_volts(self, code: f32) -> f32 {
+        code * self.width() / MAX_CODE + self.min_volts()
+    }
+
+    /// Convert a voltage to the straight binary code
+    pub fn to_code(self, volts: f32) -> c_int {
+        ((volts - self.min_volts()) / self.width() * MAX_CODE).round() as c_int
+    }
+}
+
+impl FromStr for InputRange {
+    type Err = String;
+
+    /// Parse the range written as in the manual, e.g. `+/-10V` or `2.5V`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "+/-10V" => Ok(InputRange::Bipolar10V),
+            "+/-5V" => Ok(InputRange::Bipolar5V),
+            "+/-2.5V" => Ok(InputRange::Bipolar2_5V),
+            "+/-1.25V" => Ok(InputRange::Bipolar1_25V),
+            "10V" => Ok(InputRange::Unipolar10V),
+            "5V" => Ok(InputRange::Unipolar5V),
+            "2.5V" => Ok(InputRange::Unipolar2_5V),
+            other => Err(format!("Unknown input range '{}'", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_range_code() {
+        for code in 0..=6 {
+            let range = InputRange::from_code(code, "TUSB0216AD_Input_Check").unwrap();
+            assert_eq!(range.code(), code);
+        }
+        assert_eq!(
+            InputRange::from_code(7, "TUSB0216AD_Input_Check"),
+            Err(AdError::RangeNotAccepted("TUSB0216AD_Input_Check"))
+        );
+    }
+
+    #[test]
+    fn test_volts_to_code() {
+        assert_eq!(InputRange::Bipolar10V.to_code(-10.0), 0);
+        assert_eq!(InputRange::Bipolar10V.to_code(0.0), 32768);
+        assert_eq!(InputRange::Bipolar10V.to_code(10.0), 65535);
+        assert_eq!(InputRange::Unipolar2_5V.to_code(1.25), 32768);
+    }
+
+    #[test]
+    fn test_code_to_volts() {
+        assert_eq!(InputRange::Bipolar10V.to_volts(0.0), -10.0);
+        assert_eq!(InputRange::Bipolar10V.to_volts(65535.0), 10.0);
+        assert_eq!(InputRange::Unipolar5V.to_volts(65535.0), 5.0);
+
+        let range = InputRange::Bipolar1_25V;
+        assert_eq!(range.to_code(range.to_volts(40000.0)), 40000);
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!("+/-2.5V".parse(), Ok(InputRange::Bipolar2_5V));
+        assert_eq!(" 5V".parse(), Ok(InputRange::Unipolar5V));
+        assert!("+/-2V".parse::<InputRange>().is_err());
+    }
+}
@@ -0,0 +1,687 @@
+use super::AdBackend;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::raw::{c_int, c_short, c_uchar, c_uint};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+/// Call of the driver with its arguments and outputs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "call", rename_all = "snake_case")]
+pub enum Call {
+    DeviceOpen,
+    DeviceClose,
+    DioIn {
+        data: c_uchar,
+    },
+    DioOut {
+        data: c_uchar,
+    },
+    DioChk {
+        data: c_uchar,
+    },
+    AdSingle {
+        data: [c_int; 2],
+    },
+    Start {
+        ch: c_uchar,
+        prelen: c_int,
+        trig_type: c_uchar,
+        trig_ch: c_uchar,
+    },
+    Stop,
+    AdStatus {
+        status: c_uchar,
+        overflow: [c_uchar; 2],
+        datalen: [c_uint; 2],
+    },
+    AdData {
+        ch: c_uchar,
+        /// Length requested by the caller
+        requested: c_uint,
+        data: Vec<c_int>,
+    },
+    AdclkSet {
+        clk_time: c_int,
+        sel: c_uchar,
+    },
+    LevelSet {
+        level: c_int,
+        hys: c_short,
+    },
+    InputSet {
+        type1: c_uchar,
+        type2: c_uchar,
+    },
+    InputCheck {
+        type1: c_uchar,
+        type2: c_uchar,
+    },
+    Trigger,
+}
+
+impl Call {
+    /// Name of the call. `TUSB0216AD_Ad_Data` is distinguished by the channel.
+    fn key(&self) -> (&'static str, c_uchar) {
+        match self {
+            Call::DeviceOpen => ("device_open", 0),
+            Call::DeviceClose => ("device_close", 0),
+            Call::DioIn { .. } => ("dio_in", 0),
+            Call::DioOut { .. } => ("dio_out", 0),
+            Call::DioChk { .. } => ("dio_chk", 0),
+            Call::AdSingle { .. } => ("ad_single", 0),
+            Call::Start { .. } => ("start", 0),
+            Call::Stop => ("stop", 0),
+            Call::AdStatus { .. } => ("ad_status", 0),
+            Call::AdData { ch, .. } => ("ad_data", *ch),
+            Call::AdclkSet { .. } => ("adclk_set", 0),
+            Call::LevelSet { .. } => ("level_set", 0),
+            Call::InputSet { .. } => ("input_set", 0),
+            Call::InputCheck { .. } => ("input_check", 0),
+            Call::Trigger => ("trigger", 0),
+        }
+    }
+}
+
+/// One line of a recording
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// Seconds since the recording started
+    pub time: f64,
+    pub id: c_short,
+    /// Error code returned by the driver
+    pub error: c_short,
+    #[serde(flatten)]
+    pub call: Call,
+}
+
+/// Backend writing every call of another backend to a file,
+/// one JSON `Record` per line.
+///
+/// The records are written by a background thread, so that the calls
+/// on the acquisition thread do not wait for the file.
+/// Dropping the backend writes out the remaining records.
+pub struct RecordingBackend<B: AdBackend + ?Sized> {
+    inner: Arc<B>,
+    /// Records passed to the writer thread. `None` once dropped
+    sender: Mutex<Option<Sender<Record>>>,
+    writer: Option<JoinHandle<()>>,
+    started: Instant,
+}
+
+impl<B: AdBackend + ?Sized> RecordingBackend<B> {
+    /// Record the calls of `inner` to a new file at `path`
+    pub fn create<P: AsRef<Path>>(inner: Arc<B>, path: P) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        let (sender, receiver) = mpsc::channel();
+        Ok(RecordingBackend {
+            inner,
+            sender: Mutex::new(Some(sender)),
+            writer: Some(thread::spawn(move || write_records(receiver, file))),
+            started: Instant::now(),
+        })
+    }
+
+    /// Pass a record to the writer thread and the error code through
+    fn record(&self, id: c_short, error: c_short, call: Call) -> c_short {
+        let record = Record {
+            time: self.started.elapsed().as_secs_f64(),
+            id,
+            error,
+            call,
+        };
+        if let Some(sender) = self.sender.lock().unwrap().as_ref() {
+            sender.send(record).ok();
+        }
+        error
+    }
+}
+
+impl<B: AdBackend + ?Sized> Drop for RecordingBackend<B> {
+    fn drop(&mut self) {
+        // Closing the channel lets the writer thread finish the file
+        self.sender.lock().unwrap().take();
+        if let Some(writer) = self.writer.take() {
+            writer.join().ok();
+        }
+    }
+}
+
+/// Write the records from `receiver` to `file` until the channel is closed.
+/// The file is flushed whenever the writer catches up with the calls.
+fn write_records(receiver: Receiver<Record>, mut file: BufWriter<File>) {
+    let mut failed = false;
+    let mut next = receiver.recv().ok();
+    while let Some(record) = next {
+        if !failed {
+            let result = serde_json::to_writer(&mut file, &record)
+                .map_err(io::Error::from)
+                .and_then(|_| writeln!(file));
+            if let Err(e) = result {
+                println!("Failed to record: {}", e);
+                failed = true;
+            }
+        }
+        next = match receiver.try_recv() {
+            Ok(record) => Some(record),
+            Err(TryRecvError::Empty) => {
+                file.flush().ok();
+                receiver.recv().ok()
+            }
+            Err(TryRecvError::Disconnected) => None,
+        };
+    }
+    file.flush().ok();
+}
+
+impl<B: AdBackend + ?Sized> AdBackend for RecordingBackend<B> {
+    fn device_open(&self, id: c_short) -> c_short {
+        let error = self.inner.device_open(id);
+        self.record(id, error, Call::DeviceOpen)
+    }
+
+    fn device_close(&self, id: c_short) {
+        self.inner.device_close(id);
+        self.record(id, 0, Call::DeviceClose);
+    }
+
+    fn dio_in(&self, id: c_short, data: &mut c_uchar) -> c_short {
+        let error = self.inner.dio_in(id, data);
+        self.record(id, error, Call::DioIn { data: *data })
+    }
+
+    fn dio_out(&self, id: c_short, data: c_uchar) -> c_short {
+        let error = self.inner.dio_out(id, data);
+        self.record(id, error, Call::DioOut { data })
+    }
+
+    fn dio_chk(&self, id: c_short, data: &mut c_uchar) -> c_short {
+        let error = self.inner.dio_chk(id, data);
+        self.record(id, error, Call::DioChk { data: *data })
+    }
+
+    fn ad_single(&self, id: c_short, data: &mut [c_int; 2]) -> c_short {
+        let error = self.inner.ad_single(id, data);
+        self.record(id, error, Call::AdSingle { data: *data })
+    }
+
+    fn start(
+        &self,
+        id: c_short,
+        ch: c_uchar,
+        prelen: c_int,
+        trig_type: c_uchar,
+        trig_ch: c_uchar,
+    ) -> c_short {
+        let error = self.inner.start(id, ch, prelen, trig_type, trig_ch);
+        let call = Call::Start {
+            ch,
+            prelen,
+            trig_type,
+            trig_ch,
+        };
+        self.record(id, error, call)
+    }
+
+    fn stop(&self, id: c_short) -> c_short {
+        let error = self.inner.stop(id);
+        self.record(id, error, Call::Stop)
+    }
+
+    fn ad_status(
+        &self,
+        id: c_short,
+        status: &mut c_uchar,
+        overflow: &mut [c_uchar; 2],
+        datalen: &mut [c_uint; 2],
+    ) -> c_short {
+        let error = self.inner.ad_status(id, status, overflow, datalen);
+        let call = Call::AdStatus {
+            status: *status,
+            overflow: *overflow,
+            datalen: *datalen,
+        };
+        self.record(id, error, call)
+    }
+
+    fn ad_data(
+        &self,
+        id: c_short,
+        ch: c_uchar,
+        data: &mut [c_int],
+        datalen: &mut c_uint,
+    ) -> c_short {
+        let requested = *datalen;
+        let error = self.inner.ad_data(id, ch, data, datalen);
+        let length = match error {
+            0 => (*datalen as usize).min(data.len()),
+            _ => 0,
+        };
+        let call = Call::AdData {
+            ch,
+            requested,
+            data: data[..length].to_vec(),
+        };
+        self.record(id, error, call)
+    }
+
+    fn adclk_set(&self, id: c_short, clk_time: c_int, sel: c_uchar) -> c_short {
+        let error = self.inner.adclk_set(id, clk_time, sel);
+        self.record(id, error, Call::AdclkSet { clk_time, sel })
+    }
+
+    fn level_set(&self, id: c_short, level: c_int, hys: c_short) -> c_short {
+        let error = self.inner.level_set(id, level, hys);
+        self.record(id, error, Call::LevelSet { level, hys })
+    }
+
+    fn input_set(&self, id: c_short, type1: c_uchar, type2: c_uchar) -> c_short {
+        let error = self.inner.input_set(id, type1, type2);
+        self.record(id, error, Call::InputSet { type1, type2 })
+    }
+
+    fn input_check(&self, id: c_short, type1: &mut c_uchar, type2: &mut c_uchar) -> c_short {
+        let error = self.inner.input_check(id, type1, type2);
+        let call = Call::InputCheck {
+            type1: *type1,
+            type2: *type2,
+        };
+        self.record(id, error, call)
+    }
+
+    fn trigger(&self, id: c_short) -> c_short {
+        let error = self.inner.trigger(id);
+        self.record(id, error, Call::Trigger)
+    }
+}
+
+type Key = (c_short, &'static str, c_uchar);
+
+#[derive(Debug, Default)]
+struct Replay {
+    /// Records not replayed yet, for each unit and call
+    queues: HashMap<Key, VecDeque<Record>>,
+    /// Last replayed record, answered again when the recording ran out
+    last: HashMap<Key, Record>,
+    /// Units stopped after their status polls ran out
+    stopped: HashSet<c_short>,
+}
+
+impl Replay {
+    /// Whether the next status poll of the unit `id` was recorded after
+    /// a stop which has not been replayed yet
+    fn waits_for_stop(&self, id: c_short) -> bool {
+        let front = |name| self.queues.get(&(id, name, 0)).and_then(VecDeque::front);
+        matches!(
+            (front("ad_status"), front("stop")),
+            (Some(status), Some(stop)) if status.time > stop.time
+        )
+    }
+}
+
+/// Backend answering the calls with a recording of `RecordingBackend`.
+///
+/// Each call gets the outputs of the next recorded call of the same kind
+/// on the same unit, regardless of the timing, so the same code reads the
+/// same data. When the recording runs out, the last outputs are repeated,
+/// except that no more samples are converted. The status polls recorded
+/// after `TUSB0216AD_Stop` are held back in the same way until it is called.
+#[derive(Debug)]
+pub struct ReplayBackend {
+    replay: Mutex<Replay>,
+}
+
+impl ReplayBackend {
+    /// Load the recording at `path`
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let records = BufReader::new(File::open(path)?)
+            .lines()
+            .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+            .map(|line| {
+                serde_json::from_str(&line?)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            })
+            .collect::<io::Result<Vec<Record>>>()?;
+        Ok(ReplayBackend::from_records(records))
+    }
+
+    pub fn from_records(records: Vec<Record>) -> Self {
+        let mut replay = Replay::default();
+        for record in records {
+            let (name, ch) = record.call.key();
+            replay
+                .queues
+                .entry((record.id, name, ch))
+                .or_default()
+                .push_back(record);
+        }
+        ReplayBackend {
+            replay: Mutex::new(replay),
+        }
+    }
+
+    /// Next record of the call `name` on the unit `id`,
+    /// or the last one when the recording ran out.
+    /// The boolean tells whether the record is a new one.
+    fn next(&self, id: c_short, name: &'static str, ch: c_uchar) -> Option<(Record, bool)> {
+        let mut replay = self.replay.lock().unwrap();
+        let key = (id, name, ch);
+        match replay.queues.get_mut(&key).and_then(VecDeque::pop_front) {
+            Some(record) => {
+                replay.last.insert(key, record.clone());
+                Some((record, true))
+            }
+            None => replay.last.get(&key).map(|record| (record.clone(), false)),
+        }
+    }
+
+    /// Last replayed record of the call `name` on the unit `id`
+    fn last(&self, id: c_short, name: &'static str) -> Option<(Record, bool)> {
+        let replay = self.replay.lock().unwrap();
+        replay
+            .last
+            .get(&(id, name, 0))
+            .map(|record| (record.clone(), false))
+    }
+
+    /// Error code of the next call `name` without outputs
+    fn error(&self, id: c_short, name: &'static str) -> c_short {
+        self.next(id, name, 0)
+            .map(|(record, _)| record.error)
+            .unwrap_or(0)
+    }
+}
+
+impl AdBackend for ReplayBackend {
+    fn device_open(&self, id: c_short) -> c_short {
+        self.error(id, "device_open")
+    }
+
+    fn device_close(&self, id: c_short) {
+        self.next(id, "device_close", 0);
+    }
+
+    fn dio_in(&self, id: c_short, data: &mut c_uchar) -> c_short {
+        match self.next(id, "dio_in", 0) {
+            Some((record, _)) => {
+                if let Call::DioIn { data: recorded } = record.call {
+                    *data = recorded;
+                }
+                record.error
+            }
+            None => 0,
+        }
+    }
+
+    fn dio_out(&self, id: c_short, _data: c_uchar) -> c_short {
+        self.error(id, "dio_out")
+    }
+
+    fn dio_chk(&self, id: c_short, data: &mut c_uchar) -> c_short {
+        match self.next(id, "dio_chk", 0) {
+            Some((record, _)) => {
+                if let Call::DioChk { data: recorded } = record.call {
+                    *data = recorded;
+                }
+                record.error
+            }
+            None => 0,
+        }
+    }
+
+    fn ad_single(&self, id: c_short, data: &mut [c_int; 2]) -> c_short {
+        match self.next(id, "ad_single", 0) {
+            Some((record, _)) => {
+                if let Call::AdSingle { data: recorded } = record.call {
+                    *data = recorded;
+                }
+                record.error
+            }
+            None => 0,
+        }
+    }
+
+    fn start(
+        &self,
+        id: c_short,
+        _ch: c_uchar,
+        _prelen: c_int,
+        _trig_type: c_uchar,
+        _trig_ch: c_uchar,
+    ) -> c_short {
+        self.replay.lock().unwrap().stopped.remove(&id);
+        self.error(id, "start")
+    }
+
+    fn stop(&self, id: c_short) -> c_short {
+        self.replay.lock().unwrap().stopped.insert(id);
+        self.error(id, "stop")
+    }
+
+    fn ad_status(
+        &self,
+        id: c_short,
+        status: &mut c_uchar,
+        overflow: &mut [c_uchar; 2],
+        datalen: &mut [c_uint; 2],
+    ) -> c_short {
+        let waiting = self.replay.lock().unwrap().waits_for_stop(id);
+        let next = match waiting {
+            true => self.last(id, "ad_status"),
+            false => self.next(id, "ad_status", 0),
+        };
+        let (record, new) = match next {
+            Some(next) => next,
+            None => return 0,
+        };
+        if let Call::AdStatus {
+            status: recorded_status,
+            overflow: recorded_overflow,
+            datalen: recorded_datalen,
+        } = record.call
+        {
+            *status = recorded_status;
+            *overflow = recorded_overflow;
+            *datalen = recorded_datalen;
+        }
+        if !new {
+            *datalen = [0, 0];
+            if self.replay.lock().unwrap().stopped.contains(&id) {
+                *status = 0;
+            }
+        }
+        record.error
+    }
+
+    fn ad_data(
+        &self,
+        id: c_short,
+        ch: c_uchar,
+        data: &mut [c_int],
+        datalen: &mut c_uint,
+    ) -> c_short {
+        let (mut record, new) = match self.next(id, "ad_data", ch) {
+            Some(next) => next,
+            None => {
+                *datalen = 0;
+                return 0;
+            }
+        };
+        if !new {
+            *datalen = 0;
+            return record.error;
+        }
+
+        if let Call::AdData {
+            data: recorded,
+            requested,
+            ..
+        } = &mut record.call
+        {
+            let length = recorded.len().min(data.len()).min(*datalen as usize);
+            data[..length].copy_from_slice(&recorded[..length]);
+            *datalen = length as c_uint;
+
+            // Samples not requested this time are returned by the next call
+            if length < recorded.len() {
+                recorded.drain(..length);
+                *requested -= length as c_uint;
+                let mut replay = self.replay.lock().unwrap();
+                let key = (id, "ad_data", ch);
+                replay.last.remove(&key);
+                replay
+                    .queues
+                    .entry(key)
+                    .or_default()
+                    .push_front(record.clone());
+            }
+        }
+        record.error
+    }
+
+    fn adclk_set(&self, id: c_short, _clk_time: c_int, _sel: c_uchar) -> c_short {
+        self.error(id, "adclk_set")
+    }
+
+    fn level_set(&self, id: c_short, _level: c_int, _hys: c_short) -> c_short {
+        self.error(id, "level_set")
+    }
+
+    fn input_set(&self, id: c_short, _type1: c_uchar, _type2: c_uchar) -> c_short {
+        self.error(id, "input_set")
+    }
+
+    fn input_check(&self, id: c_short, type1: &mut c_uchar, type2: &mut c_uchar) -> c_short {
+        match self.next(id, "input_check", 0) {
+            Some((record, _)) => {
+                if let Call::InputCheck {
+                    type1: recorded1,
+                    type2: recorded2,
+                } = record.call
+                {
+                    *type1 = recorded1;
+                    *type2 = recorded2;
+                }
+                record.error
+            }
+            None => 0,
+        }
+    }
+
+    fn trigger(&self, id: c_short) -> c_short {
+        self.error(id, "trigger")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::operations::{interface, SimulatedBackend};
+    use std::env;
+
+    #[test]
+    fn test_record_format() {
+        let record = Record {
+            time: 0.5,
+            id: 0,
+            error: 0,
+            call: Call::AdData {
+                ch: 1,
+                requested: 3,
+                data: vec![1, 2],
+            },
+        };
+        let line = serde_json::to_string(&record).unwrap();
+        assert_eq!(
+            line,
+            r#"{"time":0.5,"id":0,"error":0,"call":"ad_data","ch":1,"requested":3,"data":[1,2]}"#
+        );
+        assert_eq!(serde_json::from_str::<Record>(&line).unwrap(), record);
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let path = env::temp_dir().join(format!("adconverter-record-{}.jsonl", std::process::id()));
+        let recording = RecordingBackend::create(Arc::new(SimulatedBackend::new()), &path).unwrap();
+        interface::open(&recording, 0).unwrap();
+        interface::start(&recording, 0, 2, 100, 1, 0).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let mut data = [0; 100];
+        let recorded = interface::takeout_data(&recording, 0, 0, &mut data).unwrap();
+        assert_eq!(interface::dio_write(&recording, 0, 9), Ok(()));
+        drop(recording);
+
+        let replay = ReplayBackend::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        interface::open(&replay, 0).unwrap();
+        interface::start(&replay, 0, 2, 100, 1, 0).unwrap();
+
+        // 要求が短ければ残りは次の呼び出しで返る
+        let mut replayed = [0; 100];
+        assert_eq!(
+            interface::takeout_data(&replay, 0, 0, &mut replayed[..40]),
+            Ok(40)
+        );
+        let rest = interface::takeout_data(&replay, 0, 0, &mut replayed[40..]).unwrap();
+        assert_eq!(40 + rest, recorded);
+        assert_eq!(replayed, data);
+
+        // 記録が尽きたら新しいサンプルはない
+        assert_eq!(interface::takeout_data(&replay, 0, 0, &mut replayed), Ok(0));
+        assert_eq!(interface::takeout_data(&replay, 0, 1, &mut replayed), Ok(0));
+    }
+
+    #[test]
+    fn test_replay_errors() {
+        let replay = ReplayBackend::from_records(vec![Record {
+            time: 0.0,
+            id: 0,
+            error: 9,
+            call: Call::AdStatus {
+                status: 3,
+                overflow: [0, 0],
+                datalen: [10, 10],
+            },
+        }]);
+        assert!(interface::status(&replay, 0, false).is_err());
+        assert!(interface::status(&replay, 0, false).is_err());
+        assert_eq!(interface::stop(&replay, 0), Ok(()));
+    }
+
+    #[test]
+    fn test_replay_status_after_stop() {
+        let status = |time, status, datalen| Record {
+            time,
+            id: 0,
+            error: 0,
+            call: Call::AdStatus {
+                status,
+                overflow: [0, 0],
+                datalen: [datalen, datalen],
+            },
+        };
+        let stop = Record {
+            time: 0.2,
+            id: 0,
+            error: 0,
+            call: Call::Stop,
+        };
+        let replay = ReplayBackend::from_records(vec![status(0.1, 3, 10), stop, status(0.3, 0, 5)]);
+        let mut state = 0;
+        let mut overflow = [0, 0];
+        let mut datalen = [0, 0];
+
+        replay.ad_status(0, &mut state, &mut overflow, &mut datalen);
+        assert_eq!((state, datalen), (3, [10, 10]));
+        // 止めるまでは止まった後の状態を返さない
+        replay.ad_status(0, &mut state, &mut overflow, &mut datalen);
+        assert_eq!((state, datalen), (3, [0, 0]));
+        replay.stop(0);
+        replay.ad_status(0, &mut state, &mut overflow, &mut datalen);
+        assert_eq!((state, datalen), (0, [5, 5]));
+    }
+}
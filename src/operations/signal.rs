@@ -0,0 +1,267 @@
+use rand::Rng;
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+/// Shape of a simulated signal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Waveform {
+    Sine,
+    Triangle,
+    Square,
+    /// Sawtooth rising from -amplitude to +amplitude
+    Ramp,
+    /// Only the offset and the noise
+    Constant,
+}
+
+/// Signal fed to a channel of the simulator
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Signal {
+    pub waveform: Waveform,
+    /// Peak amplitude [V]
+    pub amplitude: f64,
+    /// Frequency [Hz]
+    pub frequency: f64,
+    /// DC offset [V]
+    pub offset: f64,
+    /// Peak-to-peak amplitude of the uniform noise [V]
+    pub noise: f64,
+}
+
+impl Default for Signal {
+    /// Nearly full scale sine of the +/-10 V range, as the scanning stage
+    /// driven at 20 Hz
+    fn default() -> Self {
+        Signal {
+            waveform: Waveform::Sine,
+            amplitude: 9.5,
+            frequency: 20.0,
+            offset: 0.0,
+            noise: 0.3,
+        }
+    }
+}
+
+impl Signal {
+    /// Voltage at `time` seconds
+    pub fn volts<R: Rng>(&self, time: f64, rng: &mut R) -> f64 {
+        self.level(time) + uniform_noise(self.noise, rng)
+    }
+
+    /// Voltage at `time` seconds without the noise
+    pub fn level(&self, time: f64) -> f64 {
+        let phase = (time * self.frequency).fract();
+        let shape = match self.waveform {
+            Waveform::Sine => (2.0 * PI * phase).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Waveform::Square if phase < 0.5 => 1.0,
+            Waveform::Square => -1.0,
+            Waveform::Ramp => 2.0 * phase - 1.0,
+            Waveform::Constant => 0.0,
+        };
+
+        self.amplitude * shape + self.offset
+    }
+}
+
+/// Uniform noise whose peak-to-peak amplitude is `noise`
+fn uniform_noise<R: Rng>(noise: f64, rng: &mut R) -> f64 {
+    noise * (rng.gen::<f64>() - 0.5)
+}
+
+/// Split `key=value,...` into the keys and the values
+fn parse_items<'a, I: Iterator<Item = &'a str>>(items: I) -> Result<Vec<(&'a str, f64)>, String> {
+    items
+        .map(|item| {
+            let (key, value) = item
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value, found '{}'", item))?;
+            let value = value
+                .parse()
+                .map_err(|_| format!("Invalid value of {}: '{}'", key, value))?;
+            Ok((key, value))
+        })
+        .collect()
+}
+
+/// Parse a signal written as `waveform,key=value,...`,
+/// e.g. `triangle,amplitude=5,frequency=10,offset=0.5,noise=0.01`.
+/// Omitted keys keep the default values.
+impl FromStr for Signal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut items = s.split(',').map(str::trim);
+        let mut signal = Signal {
+            waveform: match items.next().unwrap_or_default() {
+                "sine" => Waveform::Sine,
+                "triangle" => Waveform::Triangle,
+                "square" => Waveform::Square,
+                "ramp" => Waveform::Ramp,
+                "constant" => Waveform::Constant,
+                other => return Err(format!("Unknown waveform '{}'", other)),
+            },
+            ..Signal::default()
+        };
+
+        for (key, value) in parse_items(items)? {
+            match key {
+                "amplitude" => signal.amplitude = value,
+                "frequency" => signal.frequency = value,
+                "offset" => signal.offset = value,
+                "noise" => signal.noise = value,
+                _ => return Err(format!("Unknown key '{}'", key)),
+            }
+        }
+        Ok(signal)
+    }
+}
+
+/// THz transient measured by the lock-in amplifier of a rapid-scan THz-TDS
+/// setup, as a function of the delay stage position read on CH1.
+/// Positions are in volts of CH1, as binned by `update_data`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ThzPulse {
+    /// Stage position of the zero crossing of the pulse [V]
+    pub center: f64,
+    /// Distance between the peak and the zero crossing [V]
+    pub width: f64,
+    /// Height of the positive peak [V]
+    pub amplitude: f64,
+    /// Amplitude of the ringing by the water vapour, relative to `amplitude`
+    pub ringing: f64,
+    /// Period of the ringing [V]
+    pub ringing_period: f64,
+    /// Decay length of the ringing [V]
+    pub ringing_decay: f64,
+    /// Peak-to-peak amplitude of the uniform noise [V]
+    pub noise: f64,
+}
+
+impl Default for ThzPulse {
+    fn default() -> Self {
+        ThzPulse {
+            center: 0.0,
+            width: 0.5,
+            amplitude: 1.0,
+            ringing: 0.05,
+            ringing_period: 0.4,
+            ringing_decay: 3.0,
+            noise: 0.01,
+        }
+    }
+}
+
+impl ThzPulse {
+    /// Voltage at the stage position `position`
+    pub fn volts<R: Rng>(&self, position: f64, rng: &mut R) -> f64 {
+        self.level(position) + uniform_noise(self.noise, rng)
+    }
+
+    /// Voltage at the stage position `position` without the noise.
+    /// The single-cycle pulse is the derivative of a Gaussian, followed by
+    /// a decaying sinusoid.
+    pub fn level(&self, position: f64) -> f64 {
+        let delay = position - self.center;
+        let u = delay / self.width;
+        let pulse = -u * ((1.0 - u * u) / 2.0).exp();
+        let ringing = if delay > 0.0 {
+            self.ringing
+                * (2.0 * PI * delay / self.ringing_period).sin()
+                * (-delay / self.ringing_decay).exp()
+        } else {
+            0.0
+        };
+
+        self.amplitude * (pulse + ringing)
+    }
+}
+
+/// Parse a pulse written as `key=value,...`,
+/// e.g. `center=1,width=0.3,amplitude=2,ringing=0.1,noise=0.02`.
+/// The keys are the names of the fields. Omitted keys keep the default values.
+impl FromStr for ThzPulse {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut pulse = ThzPulse::default();
+        let items = s.split(',').map(str::trim).filter(|item| !item.is_empty());
+        for (key, value) in parse_items(items)? {
+            match key {
+                "center" => pulse.center = value,
+                "width" => pulse.width = value,
+                "amplitude" => pulse.amplitude = value,
+                "ringing" => pulse.ringing = value,
+                "ringing_period" => pulse.ringing_period = value,
+                "ringing_decay" => pulse.ringing_decay = value,
+                "noise" => pulse.noise = value,
+                _ => return Err(format!("Unknown key '{}'", key)),
+            }
+        }
+        Ok(pulse)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_waveforms() {
+        let mut rng = rand::thread_rng();
+        let mut signal = Signal {
+            waveform: Waveform::Triangle,
+            amplitude: 2.0,
+            frequency: 1.0,
+            offset: 1.0,
+            noise: 0.0,
+        };
+        assert_eq!(signal.volts(0.0, &mut rng), -1.0);
+        assert_eq!(signal.volts(0.5, &mut rng), 3.0);
+
+        signal.waveform = Waveform::Square;
+        assert_eq!(signal.volts(0.25, &mut rng), 3.0);
+        assert_eq!(signal.volts(0.75, &mut rng), -1.0);
+
+        signal.waveform = Waveform::Ramp;
+        assert_eq!(signal.volts(0.75, &mut rng), 2.0);
+
+        signal.waveform = Waveform::Constant;
+        signal.noise = 0.1;
+        assert!((signal.volts(0.3, &mut rng) - 1.0).abs() <= 0.05);
+    }
+
+    #[test]
+    fn test_parse_signal() {
+        let signal: Signal = "ramp, amplitude=5, offset=-1".parse().unwrap();
+        assert_eq!(signal.waveform, Waveform::Ramp);
+        assert_eq!(signal.amplitude, 5.0);
+        assert_eq!(signal.offset, -1.0);
+        assert_eq!(signal.frequency, Signal::default().frequency);
+
+        assert!("saw".parse::<Signal>().is_err());
+        assert!("sine,gain=2".parse::<Signal>().is_err());
+        assert!("sine,noise".parse::<Signal>().is_err());
+    }
+
+    #[test]
+    fn test_thz_pulse() {
+        let pulse = ThzPulse {
+            center: 1.0,
+            ringing: 0.0,
+            ..ThzPulse::default()
+        };
+        // 中心の手前で正のピーク、後ろで負のピーク
+        assert_eq!(pulse.level(1.0), 0.0);
+        assert!((pulse.level(0.5) - 1.0).abs() < 1e-12);
+        assert!((pulse.level(1.5) + 1.0).abs() < 1e-12);
+        assert!(pulse.level(-5.0).abs() < 1e-6);
+
+        let pulse: ThzPulse = "center=2, amplitude=3".parse().unwrap();
+        assert_eq!(pulse.center, 2.0);
+        assert_eq!(pulse.amplitude, 3.0);
+        assert_eq!(pulse.width, ThzPulse::default().width);
+        assert_eq!("".parse::<ThzPulse>(), Ok(ThzPulse::default()));
+        assert!("delay=1".parse::<ThzPulse>().is_err());
+    }
+}
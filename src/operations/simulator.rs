@@ -0,0 +1,848 @@
+use super::clock::{self, Clock};
+use super::fault::{Fault, ScheduledFault};
+use super::signal::{Signal, ThzPulse};
+use super::{AdBackend, InputRange};
+use rand::Rng;
+use std::collections::BTreeMap;
+use std::os::raw::{c_int, c_short, c_uchar, c_uint};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Virtual digital I/O port
+#[derive(Debug, Default)]
+struct DioPort {
+    input: c_uchar,
+    output: c_uchar,
+}
+
+/// Samples held by the device memory per channel when both channels are converted
+const BUFFER_LENGTH: u64 = 262144;
+
+/// Continuous sampling started by `TUSB0216AD_Start`
+#[derive(Debug)]
+struct Sampling {
+    /// `ch` of `TUSB0216AD_Start`
+    ch: c_uchar,
+    prelen: u64,
+    /// Sampling rate [Hz]
+    rate: f64,
+    started: Instant,
+    /// When the trigger fires. `None` while waiting for the software trigger
+    triggered: Option<Instant>,
+    stopped: Option<Instant>,
+    /// When the conversion stalled without being stopped
+    stalled: Option<Instant>,
+    /// Samples taken out of CH1, CH2
+    taken: [u64; 2],
+}
+
+impl Sampling {
+    fn converts(&self, ch: usize) -> bool {
+        self.ch == 2 || self.ch as usize == ch
+    }
+
+    /// A single channel can use the whole memory of the device
+    fn capacity(&self) -> u64 {
+        match self.ch {
+            2 => BUFFER_LENGTH,
+            _ => 2 * BUFFER_LENGTH,
+        }
+    }
+
+    /// When the conversion ended, or now if it goes on
+    fn end(&self) -> Instant {
+        [self.stopped, self.stalled]
+            .iter()
+            .flatten()
+            .min()
+            .copied()
+            .unwrap_or_else(Instant::now)
+    }
+
+    /// Whether the trigger has fired by `end`
+    fn is_triggered(&self, end: Instant) -> bool {
+        matches!(self.triggered, Some(triggered) if triggered <= end)
+    }
+
+    /// Pre-trigger samples held in the memory. They fill up from the start
+    /// until the trigger fires, and at most `prelen` of them are kept
+    fn held(&self) -> u64 {
+        let end = self.end();
+        let filled_until = match self.triggered {
+            Some(triggered) if triggered <= end => triggered,
+            _ => end,
+        };
+        let elapsed = filled_until.saturating_duration_since(self.started);
+        self.prelen
+            .min((elapsed.as_secs_f64() * self.rate).round() as u64)
+    }
+
+    /// Samples converted so far, including the pre-trigger samples
+    fn converted(&self) -> u64 {
+        let end = self.end();
+        let after_trigger = match self.triggered {
+            Some(triggered) => end.saturating_duration_since(triggered).as_secs_f64(),
+            None => 0.0,
+        };
+        self.held() + (after_trigger * self.rate) as u64
+    }
+}
+
+/// State of one simulated unit
+#[derive(Debug)]
+struct Unit {
+    dio: DioPort,
+    overflow: [c_uchar; 2],
+    /// Input range numbers of CH1, CH2
+    ranges: [c_uchar; 2],
+    /// Ignore `TUSB0216AD_Input_Set`, as a unit failing to change its range
+    ranges_locked: bool,
+    /// Arguments of `TUSB0216AD_AdClk_Set`
+    clk_time: c_int,
+    sel: c_uchar,
+    sampling: Option<Sampling>,
+    opened: bool,
+    /// Faults to occur after each `TUSB0216AD_Device_Open`
+    schedule: Vec<ScheduledFault>,
+    /// When the unit was opened last
+    opened_at: Option<Instant>,
+    /// Faults since the unit was opened last, and when they occur
+    faults: Vec<(Instant, Fault)>,
+}
+
+impl Default for Unit {
+    fn default() -> Self {
+        Unit {
+            dio: DioPort::default(),
+            overflow: [0, 0],
+            ranges: [0, 0],
+            ranges_locked: false,
+            clk_time: clock::MIN_CLK_TIME,
+            sel: 0,
+            sampling: None,
+            opened: false,
+            schedule: vec![],
+            opened_at: None,
+            faults: vec![],
+        }
+    }
+}
+
+impl Unit {
+    /// Schedule the faults from the time the unit is opened
+    fn open_faults(&mut self, opened_at: Instant) {
+        self.opened_at = Some(opened_at);
+        self.faults = self
+            .schedule
+            .iter()
+            .map(|scheduled| (opened_at + scheduled.after, scheduled.fault))
+            .collect();
+    }
+
+    /// Continuous sampling started and not stopped yet
+    fn running(&self) -> bool {
+        matches!(&self.sampling, Some(sampling) if sampling.stopped.is_none())
+    }
+
+    fn range(&self, ch: usize) -> InputRange {
+        InputRange::from_code(self.ranges[ch], "TUSB0216AD_Input_Check")
+            .unwrap_or(InputRange::Bipolar10V)
+    }
+
+    /// Faults which have occurred, with the time they occurred
+    fn active_faults(&self) -> impl Iterator<Item = (Instant, Fault)> + '_ {
+        let now = Instant::now();
+        self.faults
+            .iter()
+            .copied()
+            .filter(move |(at, _)| *at <= now)
+    }
+
+    /// Error code returned by every call after a fault
+    fn failure(&self) -> Option<c_short> {
+        self.active_faults().find_map(|(_, fault)| match fault {
+            Fault::UsbError => Some(9),
+            Fault::Disconnected => Some(6),
+            _ => None,
+        })
+    }
+
+    /// Samples returned by one call of `TUSB0216AD_Ad_Data`
+    fn max_data_length(&self) -> c_uint {
+        self.active_faults()
+            .filter_map(|(_, fault)| match fault {
+                Fault::ShortData(length) => Some(length),
+                _ => None,
+            })
+            .min()
+            .unwrap_or(c_uint::MAX)
+    }
+
+    /// Samples waiting to be taken out of CH1, CH2.
+    /// When more samples than the memory can hold have been converted,
+    /// the oldest ones are lost and the overflow flag is set.
+    fn pending(&mut self) -> [u64; 2] {
+        let now = Instant::now();
+        let mut overflowed = false;
+        if let Some(sampling) = &mut self.sampling {
+            for (at, fault) in self.faults.iter().filter(|(at, _)| *at <= now) {
+                match fault {
+                    // A fault scheduled before this sampling stalls it from the start
+                    Fault::StalledStatus => {
+                        sampling.stalled.get_or_insert((*at).max(sampling.started));
+                    }
+                    Fault::Overflow => overflowed = true,
+                    _ => (),
+                }
+            }
+        }
+        // An overflow occurs only once
+        if overflowed {
+            self.faults
+                .retain(|(at, fault)| *fault != Fault::Overflow || *at > now);
+        }
+
+        let mut pending = [0, 0];
+        if let Some(sampling) = &mut self.sampling {
+            let converted = sampling.converted();
+            let capacity = sampling.capacity();
+            let channels: Vec<usize> = (0..2).filter(|ch| sampling.converts(*ch)).collect();
+            for ch in channels {
+                let taken = &mut sampling.taken[ch];
+                if overflowed {
+                    *taken = converted;
+                    self.overflow[ch] = 1;
+                }
+                if converted.saturating_sub(*taken) > capacity {
+                    *taken = converted - capacity;
+                    self.overflow[ch] = 1;
+                }
+                pending[ch] = converted.saturating_sub(*taken);
+            }
+        }
+        pending
+    }
+}
+
+/// Backend imitating TUSB-0216ADMZ units.
+///
+/// The samples are converted at the configured clock as the wall time
+/// passes, and quantised to 16-bit codes in the configured input ranges.
+/// The hardware triggers fire as soon as `prelen` pre-trigger samples are
+/// held, and the software trigger fires whenever `TUSB0216AD_Trigger` is called.
+#[derive(Debug)]
+pub struct SimulatedBackend {
+    units: Mutex<BTreeMap<c_short, Unit>>,
+    /// Signals fed to CH1, CH2
+    signals: [Signal; 2],
+    /// Rapid-scan THz-TDS: CH2 is this pulse at the stage position on CH1,
+    /// instead of `signals[1]`
+    pulse: Option<ThzPulse>,
+    /// Rate assumed for the external clock [Hz]
+    external_rate: f64,
+    /// Origin of the time of `TUSB0216AD_Ad_Single`
+    created: Instant,
+}
+
+impl Default for SimulatedBackend {
+    fn default() -> Self {
+        SimulatedBackend::with_units(&[0])
+    }
+}
+
+impl SimulatedBackend {
+    /// A unit whose unit switch is set to 0
+    pub fn new() -> Self {
+        SimulatedBackend::default()
+    }
+
+    /// Units whose unit switches are set to `ids`
+    pub fn with_units(ids: &[c_short]) -> Self {
+        let units = ids.iter().map(|id| (*id, Unit::default())).collect();
+        SimulatedBackend {
+            units: Mutex::new(units),
+            signals: [Signal::default(), Signal::default()],
+            pulse: None,
+            external_rate: 100e3,
+            created: Instant::now(),
+        }
+    }
+
+    /// Feed `signal` to the channel `ch` (0: CH1, 1: CH2)
+    pub fn with_signal(mut self, ch: usize, signal: Signal) -> Self {
+        self.signals[ch] = signal;
+        self
+    }
+
+    /// Simulate a rapid-scan THz-TDS experiment: CH1 reads the delay stage
+    /// swept by the signal of CH1, and CH2 the THz `pulse` at the stage position
+    pub fn with_thz_pulse(mut self, pulse: ThzPulse) -> Self {
+        self.pulse = Some(pulse);
+        self
+    }
+
+    /// Rate of the clock fed to the external clock input [Hz]
+    pub fn with_external_rate(mut self, rate: f64) -> Self {
+        self.external_rate = rate;
+        self
+    }
+
+    /// Set the levels of the lines read by `TUSB0216AD_DIO_In` of the unit `id`
+    #[cfg(test)]
+    pub fn set_dio_input(&self, id: c_short, data: c_uchar) {
+        self.with_attached(id, |unit| unit.dio.input = data);
+    }
+
+    /// Set the overflow flags reported by `TUSB0216AD_Ad_Status` of the unit `id`
+    #[cfg(test)]
+    pub fn set_overflow(&self, id: c_short, overflow: [c_uchar; 2]) {
+        self.with_attached(id, |unit| unit.overflow = overflow);
+    }
+
+    /// Make the unit `id` ignore the input ranges set afterwards
+    #[cfg(test)]
+    pub fn lock_ranges(&self, id: c_short) {
+        self.with_attached(id, |unit| unit.ranges_locked = true);
+    }
+
+    /// Make `fault` occur on the unit `id` `after` it is opened.
+    /// The time is measured again every time the unit is opened.
+    /// If that time has already passed, the fault occurs from now on.
+    pub fn inject(&self, id: c_short, fault: Fault, after: Duration) {
+        self.with_attached(id, |unit| {
+            unit.schedule.push(ScheduledFault { fault, after });
+            if let Some(opened_at) = unit.opened_at.filter(|_| unit.opened) {
+                let at = (opened_at + after).max(Instant::now());
+                unit.faults.push((at, fault));
+            }
+        });
+    }
+
+    /// Make `faults` occur on all the units
+    pub fn with_faults(self, faults: &[ScheduledFault]) -> Self {
+        let ids: Vec<c_short> = self.units.lock().unwrap().keys().copied().collect();
+        for id in ids {
+            for fault in faults {
+                self.inject(id, fault.fault, fault.after);
+            }
+        }
+        self
+    }
+
+    /// Run `f` on the unit `id` whatever its state is.
+    /// Returns `None` when no such unit is attached.
+    fn with_attached<T, F: FnOnce(&mut Unit) -> T>(&self, id: c_short, f: F) -> Option<T> {
+        self.units.lock().unwrap().get_mut(&id).map(f)
+    }
+
+    /// Run `f` on the opened unit `id`.
+    /// Returns error code 5 when no such unit is attached or it is not opened,
+    /// and the code of the fault after a fault making the unit fail.
+    fn with_unit<F: FnOnce(&mut Unit)>(&self, id: c_short, f: F) -> c_short {
+        self.with_attached(id, |unit| match unit.failure() {
+            Some(code) => code,
+            None if !unit.opened => 5,
+            None => {
+                f(unit);
+                0
+            }
+        })
+        .unwrap_or(5)
+    }
+
+    /// Run `f` on the opened unit `id` when it is not sampling continuously.
+    /// Returns error code 11 during the continuous sampling.
+    fn with_idle_unit<F: FnOnce(&mut Unit)>(&self, id: c_short, f: F) -> c_short {
+        let mut busy = false;
+        let error = self.with_unit(id, |unit| match unit.running() {
+            true => busy = true,
+            false => f(unit),
+        });
+        match (error, busy) {
+            (0, true) => 11,
+            _ => error,
+        }
+    }
+
+    fn check_id(&self, id: c_short) -> c_short {
+        self.with_unit(id, |_| ())
+    }
+
+    /// Code converted from the channel `ch` at `time` seconds
+    fn sample<R: Rng>(&self, unit: &Unit, ch: usize, time: f64, rng: &mut R) -> c_int {
+        let volts = match (ch, &self.pulse) {
+            (1, Some(pulse)) => pulse.volts(self.signals[0].level(time), rng),
+            _ => self.signals[ch].volts(time, rng),
+        };
+        unit.range(ch).to_code(volts as f32).clamp(0, 65535)
+    }
+
+    /// Sampling rate of the clock set to `unit`
+    fn rate(&self, unit: &Unit) -> f64 {
+        match unit.sel {
+            0 => Clock::Internal(unit.clk_time).rate(),
+            _ => self.external_rate,
+        }
+    }
+}
+
+impl AdBackend for SimulatedBackend {
+    fn device_open(&self, id: c_short) -> c_short {
+        self.with_attached(id, |unit| {
+            if !unit.opened {
+                unit.open_faults(Instant::now());
+            }
+            match unit.failure() {
+                Some(code) => code,
+                None if unit.opened => 3,
+                None => {
+                    unit.opened = true;
+                    0
+                }
+            }
+        })
+        .unwrap_or(5)
+    }
+
+    fn device_close(&self, id: c_short) {
+        self.with_attached(id, |unit| {
+            // Closing stops the sampling
+            if let Some(sampling) = &mut unit.sampling {
+                sampling.stopped.get_or_insert_with(Instant::now);
+            }
+            unit.opened = false;
+        });
+    }
+
+    fn dio_in(&self, id: c_short, data: &mut c_uchar) -> c_short {
+        self.with_unit(id, |unit| *data = unit.dio.input)
+    }
+
+    fn dio_out(&self, id: c_short, data: c_uchar) -> c_short {
+        self.with_unit(id, |unit| unit.dio.output = data)
+    }
+
+    fn dio_chk(&self, id: c_short, data: &mut c_uchar) -> c_short {
+        self.with_unit(id, |unit| *data = unit.dio.output)
+    }
+
+    fn ad_single(&self, id: c_short, data: &mut [c_int; 2]) -> c_short {
+        let mut rng = rand::thread_rng();
+        let time = self.created.elapsed().as_secs_f64();
+        self.with_idle_unit(id, |unit| {
+            *data = [
+                self.sample(unit, 0, time, &mut rng),
+                self.sample(unit, 1, time, &mut rng),
+            ];
+        })
+    }
+
+    fn start(
+        &self,
+        id: c_short,
+        ch: c_uchar,
+        prelen: c_int,
+        trig_type: c_uchar,
+        trig_ch: c_uchar,
+    ) -> c_short {
+        if ch > 2 || prelen < 0 || trig_type > 3 || trig_ch > 1 {
+            return 8;
+        }
+        self.with_idle_unit(id, |unit| {
+            let now = Instant::now();
+            let rate = self.rate(unit);
+            // A hardware trigger which would take forever never fires
+            let fill_time = clock::sampling_time(prelen as u64, rate);
+            unit.overflow = [0, 0];
+            unit.sampling = Some(Sampling {
+                ch,
+                prelen: prelen as u64,
+                rate,
+                started: now,
+                triggered: match trig_type {
+                    0 => None,
+                    _ => fill_time.and_then(|fill_time| now.checked_add(fill_time)),
+                },
+                stopped: None,
+                stalled: None,
+                taken: [0, 0],
+            });
+        })
+    }
+
+    fn stop(&self, id: c_short) -> c_short {
+        self.with_unit(id, |unit| {
+            if let Some(sampling) = &mut unit.sampling {
+                sampling.stopped.get_or_insert_with(Instant::now);
+            }
+        })
+    }
+
+    fn ad_status(
+        &self,
+        id: c_short,
+        status: &mut c_uchar,
+        overflow: &mut [c_uchar; 2],
+        datalen: &mut [c_uint; 2],
+    ) -> c_short {
+        self.with_unit(id, |unit| {
+            let pending = unit.pending();
+            *status = match &unit.sampling {
+                Some(sampling) if sampling.stopped.is_some() => 0,
+                Some(sampling) if sampling.is_triggered(Instant::now()) => 3,
+                Some(_) => 1,
+                None => 0,
+            };
+            *overflow = unit.overflow;
+            *datalen = [pending[0] as c_uint, pending[1] as c_uint];
+        })
+    }
+
+    fn ad_data(
+        &self,
+        id: c_short,
+        ch: c_uchar,
+        data: &mut [c_int],
+        datalen: &mut c_uint,
+    ) -> c_short {
+        if ch != 0 && ch != 1 {
+            return match self.check_id(id) {
+                0 => 8,
+                error => error,
+            };
+        }
+        let ch = ch as usize;
+
+        let mut rng = rand::thread_rng();
+        self.with_unit(id, |unit| {
+            let pending = unit.pending()[ch];
+            let length = (*datalen as u64)
+                .min(data.len() as u64)
+                .min(pending)
+                .min(unit.max_data_length() as u64) as usize;
+
+            if let Some(sampling) = &unit.sampling {
+                let first = sampling.taken[ch] as f64 - sampling.held() as f64;
+                for (i, value) in data.iter_mut().take(length).enumerate() {
+                    let index = first + i as f64;
+                    *value = self.sample(unit, ch, index / sampling.rate, &mut rng);
+                }
+            }
+            if let Some(sampling) = &mut unit.sampling {
+                sampling.taken[ch] += length as u64;
+            }
+            *datalen = length as c_uint;
+        })
+    }
+
+    fn adclk_set(&self, id: c_short, clk_time: c_int, sel: c_uchar) -> c_short {
+        let mut error = self.check_id(id);
+
+        if clk_time < 500 {
+            error = 8;
+        }
+
+        if sel != 0 && sel != 1 {
+            error = 8;
+        }
+
+        if error == 0 {
+            error = self.with_idle_unit(id, |unit| {
+                unit.clk_time = clk_time;
+                unit.sel = sel;
+            });
+        }
+        error
+    }
+
+    fn level_set(&self, id: c_short, level: c_int, hys: c_short) -> c_short {
+        if !(1..=65534).contains(&level) || !(0..=660).contains(&hys) {
+            return 8;
+        }
+        self.with_idle_unit(id, |_| ())
+    }
+
+    fn input_set(&self, id: c_short, type1: c_uchar, type2: c_uchar) -> c_short {
+        if type1 > 6 || type2 > 6 {
+            return 8;
+        }
+        self.with_idle_unit(id, |unit| {
+            if !unit.ranges_locked {
+                unit.ranges = [type1, type2];
+            }
+        })
+    }
+
+    fn input_check(&self, id: c_short, type1: &mut c_uchar, type2: &mut c_uchar) -> c_short {
+        self.with_unit(id, |unit| {
+            *type1 = unit.ranges[0];
+            *type2 = unit.ranges[1];
+        })
+    }
+
+    fn trigger(&self, id: c_short) -> c_short {
+        self.with_unit(id, |unit| {
+            if let Some(sampling) = &mut unit.sampling {
+                let now = Instant::now();
+                if !sampling.is_triggered(now) {
+                    sampling.triggered = Some(now);
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::operations::signal::Waveform;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_data_grows_with_clock() {
+        let backend = SimulatedBackend::new();
+        backend.device_open(0);
+        let mut status = 0;
+        let mut overflow = [0, 0];
+        let mut datalen = [0, 0];
+
+        // 100 kHz, ソフトウェアトリガ
+        assert_eq!(backend.adclk_set(0, 500, 0), 0);
+        assert_eq!(backend.start(0, 2, 0, 0, 0), 0);
+        backend.ad_status(0, &mut status, &mut overflow, &mut datalen);
+        assert_eq!((status, datalen), (1, [0, 0]));
+
+        backend.trigger(0);
+        thread::sleep(Duration::from_millis(50));
+        backend.ad_status(0, &mut status, &mut overflow, &mut datalen);
+        assert_eq!(status, 3);
+        assert!((4000..10000).contains(&datalen[0]), "{:?}", datalen);
+
+        backend.stop(0);
+        backend.ad_status(0, &mut status, &mut overflow, &mut datalen);
+        assert_eq!(status, 0);
+        let mut data = vec![0; 100000];
+        let mut length = data.len() as c_uint;
+        backend.ad_data(0, 0, &mut data, &mut length);
+        assert_eq!(length, datalen[0]);
+
+        // 取り出した分は残っていない
+        backend.ad_data(0, 0, &mut data, &mut length);
+        assert_eq!(length, 0);
+    }
+
+    #[test]
+    fn test_pretrigger_fill() {
+        let backend = SimulatedBackend::new();
+        backend.device_open(0);
+        let mut status = 0;
+        let mut overflow = [0, 0];
+        let mut datalen = [0, 0];
+
+        // 100 kHz でトリガ前の1000点は10 msで溜まり、それ以上は残らない
+        assert_eq!(backend.start(0, 2, 1000, 0, 0), 0);
+        thread::sleep(Duration::from_millis(30));
+        backend.ad_status(0, &mut status, &mut overflow, &mut datalen);
+        assert_eq!((status, datalen), (1, [1000, 1000]));
+        backend.stop(0);
+
+        // 溜まる前にトリガを掛けると、トリガ前のサンプルはそれまでの分だけになる
+        assert_eq!(backend.start(0, 2, 100000, 0, 0), 0);
+        thread::sleep(Duration::from_millis(10));
+        backend.trigger(0);
+        backend.stop(0);
+        backend.ad_status(0, &mut status, &mut overflow, &mut datalen);
+        assert!((500..10000).contains(&datalen[0]), "{:?}", datalen);
+
+        // ハードウェアトリガはトリガ前のサンプルが溜まってから掛かる
+        assert_eq!(backend.start(0, 2, 1000, 1, 0), 0);
+        backend.ad_status(0, &mut status, &mut overflow, &mut datalen);
+        assert_eq!(status, 1);
+        thread::sleep(Duration::from_millis(20));
+        backend.ad_status(0, &mut status, &mut overflow, &mut datalen);
+        assert_eq!(status, 3);
+    }
+
+    #[test]
+    fn test_quantisation_and_clipping() {
+        let signal = Signal {
+            waveform: Waveform::Constant,
+            amplitude: 0.0,
+            frequency: 0.0,
+            offset: 7.5,
+            noise: 0.0,
+        };
+        let backend = SimulatedBackend::new()
+            .with_signal(0, signal)
+            .with_signal(1, signal);
+        backend.device_open(0);
+
+        // +/-10 V では表現できるが +/-5 V では振り切れる
+        backend.input_set(0, 0, 1);
+        let mut data = [0; 2];
+        backend.ad_single(0, &mut data);
+        assert_eq!(data, [InputRange::Bipolar10V.to_code(7.5), 65535]);
+    }
+
+    #[test]
+    fn test_overflow() {
+        let backend = SimulatedBackend::new().with_external_rate(50e6);
+        backend.device_open(0);
+        let mut status = 0;
+        let mut overflow = [0, 0];
+        let mut datalen = [0, 0];
+
+        // 50 MHz の外部クロックで CH1 のみ
+        backend.adclk_set(0, 500, 1);
+        backend.start(0, 0, 0, 1, 0);
+        thread::sleep(Duration::from_millis(20));
+        backend.ad_status(0, &mut status, &mut overflow, &mut datalen);
+        assert_eq!(overflow, [1, 0]);
+        assert_eq!(datalen, [2 * BUFFER_LENGTH as c_uint, 0]);
+    }
+
+    #[test]
+    fn test_usb_error_and_disconnect() {
+        let backend = SimulatedBackend::with_units(&[0, 1]);
+        backend.device_open(0);
+        backend.inject(0, Fault::UsbError, Duration::from_millis(20));
+        backend.inject(1, Fault::Disconnected, Duration::from_secs(0));
+
+        let mut data = 0;
+        assert_eq!(backend.dio_in(0, &mut data), 0);
+        assert_eq!(backend.device_open(1), 6);
+
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(backend.dio_in(0, &mut data), 9);
+        assert_eq!(backend.ad_data(0, 2, &mut [0; 10], &mut 10), 9);
+    }
+
+    #[test]
+    fn test_faults_from_open() {
+        let backend = SimulatedBackend::new();
+        backend.inject(0, Fault::UsbError, Duration::from_millis(20));
+        let mut data = 0;
+
+        // 作成からではなく開いてから数える
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(backend.device_open(0), 0);
+        assert_eq!(backend.dio_in(0, &mut data), 0);
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(backend.dio_in(0, &mut data), 9);
+
+        // 開き直すと改めて数える
+        backend.device_close(0);
+        assert_eq!(backend.device_open(0), 0);
+        assert_eq!(backend.dio_in(0, &mut data), 0);
+    }
+
+    #[test]
+    fn test_invalid_parameters() {
+        let backend = SimulatedBackend::new();
+        backend.device_open(0);
+        let mut data = [0; 10];
+        let mut length = 10;
+
+        // 装置と同じく、範囲外の引数には8を返す
+        assert_eq!(backend.start(0, 3, 0, 0, 0), 8);
+        assert_eq!(backend.start(0, 2, -1, 0, 0), 8);
+        assert_eq!(backend.start(0, 2, 0, 4, 0), 8);
+        assert_eq!(backend.start(0, 2, 0, 2, 2), 8);
+        assert_eq!(backend.input_set(0, 7, 0), 8);
+        assert_eq!(backend.input_set(0, 0, 7), 8);
+        assert_eq!(backend.adclk_set(0, 499, 0), 8);
+        assert_eq!(backend.adclk_set(0, 500, 2), 8);
+        assert_eq!(backend.level_set(0, 0, 0), 8);
+        assert_eq!(backend.level_set(0, 32768, 661), 8);
+        assert_eq!(backend.ad_data(0, 2, &mut data, &mut length), 8);
+    }
+
+    #[test]
+    fn test_stall_after_taken() {
+        let backend = SimulatedBackend::new();
+        backend.device_open(0);
+        let mut status = 0;
+        let mut overflow = [0, 0];
+        let mut datalen = [0, 0];
+
+        backend.start(0, 0, 0, 0, 0);
+        backend.trigger(0);
+        thread::sleep(Duration::from_millis(20));
+        let mut data = [0; 4000];
+        let mut length = 4000;
+        backend.ad_data(0, 0, &mut data, &mut length);
+        assert!(length > 0);
+
+        // 開いた時刻から数えると過ぎている障害は、取り出したサンプルより前には遡らない
+        backend.inject(0, Fault::StalledStatus, Duration::from_secs(0));
+        backend.ad_status(0, &mut status, &mut overflow, &mut datalen);
+        assert_eq!(status, 3);
+        assert_eq!(overflow, [0, 0]);
+        assert!(datalen[0] < 4000, "{:?}", datalen);
+
+        let stalled = datalen;
+        thread::sleep(Duration::from_millis(10));
+        backend.ad_status(0, &mut status, &mut overflow, &mut datalen);
+        assert_eq!(datalen, stalled);
+    }
+
+    #[test]
+    fn test_overflow_fault() {
+        let backend = SimulatedBackend::new();
+        backend.device_open(0);
+        let mut status = 0;
+        let mut overflow = [0, 0];
+        let mut datalen = [0, 0];
+
+        backend.start(0, 2, 1000, 1, 0);
+        backend.inject(0, Fault::Overflow, Duration::from_secs(0));
+        backend.ad_status(0, &mut status, &mut overflow, &mut datalen);
+        assert_eq!(overflow, [1, 1]);
+        assert!(datalen[0] < 1000);
+
+        // 一度だけ起きる
+        backend.stop(0);
+        backend.start(0, 2, 1000, 1, 0);
+        thread::sleep(Duration::from_millis(20));
+        backend.ad_status(0, &mut status, &mut overflow, &mut datalen);
+        assert_eq!(overflow, [0, 0]);
+        assert!(datalen[0] >= 1000);
+    }
+
+    #[test]
+    fn test_short_data_and_stall() {
+        let backend = SimulatedBackend::new().with_faults(&[
+            ScheduledFault {
+                fault: Fault::ShortData(100),
+                after: Duration::from_secs(0),
+            },
+            ScheduledFault {
+                fault: Fault::StalledStatus,
+                after: Duration::from_millis(20),
+            },
+        ]);
+        backend.device_open(0);
+        let mut status = 0;
+        let mut overflow = [0, 0];
+        let mut datalen = [0, 0];
+
+        // 100 kHz でトリガ前の1000点は10 msで溜まる
+        backend.start(0, 2, 1000, 1, 0);
+        thread::sleep(Duration::from_millis(30));
+        backend.ad_status(0, &mut status, &mut overflow, &mut datalen);
+        assert_eq!(status, 3);
+        assert!(datalen[0] >= 1000, "{:?}", datalen);
+
+        // 止まったまま増えない
+        let stalled = datalen;
+        thread::sleep(Duration::from_millis(10));
+        backend.ad_status(0, &mut status, &mut overflow, &mut datalen);
+        assert_eq!((status, datalen), (3, stalled));
+
+        let mut data = [0; 1000];
+        let mut length = 1000;
+        backend.ad_data(0, 0, &mut data, &mut length);
+        assert_eq!(length, 100);
+    }
+}
@@ -0,0 +1,102 @@
+use super::AdError;
+use std::os::raw::{c_int, c_short, c_uchar};
+
+/// Edge of the analog trigger
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    Rising,
+    Falling,
+}
+
+/// Reference level of the analog trigger
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriggerLevel {
+    /// Straight binary code, 1 ~ 65534
+    Code(c_int),
+    /// Voltage converted with the input range of the trigger channel
+    Volts(f32),
+}
+
+/// Trigger starting the continuous sampling
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    /// Started by `TUSB0216AD_Trigger`
+    Software,
+    /// External digital trigger
+    External,
+    /// Analog trigger on CH1 (`ch` = 0) or CH2 (`ch` = 1).
+    /// `hysteresis` (0 ~ 660) should be larger than the noise and
+    /// smaller than the signal amplitude.
+    Analog {
+        ch: c_uchar,
+        edge: Edge,
+        level: TriggerLevel,
+        hysteresis: c_short,
+    },
+}
+
+impl Trigger {
+    /// Build a trigger from the arguments of `TUSB0216AD_Start` and `TUSB0216AD_Level_Set`.
+    /// `trig_ch`, `level` and `hysteresis` are ignored unless `trig_type` is 2 or 3.
+    pub fn from_raw(
+        trig_type: c_uchar,
+        trig_ch: c_uchar,
+        level: TriggerLevel,
+        hysteresis: c_short,
+    ) -> Result<Self, AdError> {
+        let edge = match trig_type {
+            0 => return Ok(Trigger::Software),
+            1 => return Ok(Trigger::External),
+            2 => Edge::Rising,
+            3 => Edge::Falling,
+            _ => return Err(AdError::InvalidParameters("TUSB0216AD_Start")),
+        };
+
+        Ok(Trigger::Analog {
+            ch: trig_ch,
+            edge,
+            level,
+            hysteresis,
+        })
+    }
+
+    /// `TrigType` of `TUSB0216AD_Start`
+    pub fn trig_type(&self) -> c_uchar {
+        match self {
+            Trigger::Software => 0,
+            Trigger::External => 1,
+            Trigger::Analog {
+                edge: Edge::Rising, ..
+            } => 2,
+            Trigger::Analog {
+                edge: Edge::Falling,
+                ..
+            } => 3,
+        }
+    }
+
+    /// `TrigCh` of `TUSB0216AD_Start`
+    pub fn trig_ch(&self) -> c_uchar {
+        match self {
+            Trigger::Analog { ch, .. } => *ch,
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_trigger_from_raw() {
+        let level = TriggerLevel::Code(40000);
+        assert_eq!(Trigger::from_raw(0, 1, level, 0), Ok(Trigger::Software));
+        assert_eq!(Trigger::from_raw(1, 0, level, 0), Ok(Trigger::External));
+        assert!(Trigger::from_raw(4, 0, level, 0).is_err());
+
+        let trigger = Trigger::from_raw(3, 1, level, 100).unwrap();
+        assert_eq!(trigger.trig_type(), 3);
+        assert_eq!(trigger.trig_ch(), 1);
+    }
+}
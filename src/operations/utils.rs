@@ -1,21 +1,33 @@
-/// エラーコードからエラーの詳細を表示する
+use super::AdError;
+use std::os::raw::c_short;
+
+/// エラーコードをResultに変換する
 /// # Arguments
 ///
 /// * e - エラーコード
 /// * func_name - エラーの発生元のメソッド名
-pub fn parse_error(e: i16, func_name: &str) {
-    match e {
-        0 => {}
-        1 => println!("{}: Invalid ID", func_name),
-        2 => println!("{}: Invalid Driver", func_name),
-        3 => println!("{}: Device already opened", func_name),
-        4 => println!("{}: Too many devices", func_name),
-        5 => println!("{}: Failed to open device", func_name),
-        6 => println!("{}: Device not found", func_name),
-        8 => println!("{}: Parameters are invalid", func_name),
-        9 => println!("{}: USB connection error", func_name),
-        11 => println!("{}: Sequential reading", func_name),
-        99 => println!("{}: Other error", func_name),
-        _ => println!("{}: Other error", func_name),
+pub fn parse_error(e: c_short, func_name: &'static str) -> Result<(), AdError> {
+    match AdError::from_code(e, func_name) {
+        None => Ok(()),
+        Some(error) => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_error() {
+        assert_eq!(parse_error(0, "TUSB0216AD_Start"), Ok(()));
+        assert_eq!(
+            parse_error(9, "TUSB0216AD_Ad_Data"),
+            Err(AdError::UsbError("TUSB0216AD_Ad_Data"))
+        );
+
+        let error = parse_error(42, "TUSB0216AD_Trigger").unwrap_err();
+        assert_eq!(error, AdError::Other("TUSB0216AD_Trigger", 42));
+        assert_eq!(error.code(), 42);
+        assert_eq!(error.to_string(), "TUSB0216AD_Trigger: Other error (42)");
     }
 }
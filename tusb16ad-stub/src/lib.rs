@@ -0,0 +1,200 @@
+//! Stub of the TUSB16AD driver for the tests of `DriverBackend`.
+//!
+//! Only the unit 0 is attached. While sampling, each channel has 1000
+//! converted samples, CH1 counting up from 0 and CH2 down from 65535.
+#![allow(non_snake_case, clippy::missing_safety_doc)]
+
+use std::os::raw::{c_int, c_short, c_uchar, c_uint};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+/// Samples available on each channel while sampling
+const DATALEN: c_uint = 1000;
+
+static OPENED: AtomicBool = AtomicBool::new(false);
+/// 0: stopped, 3: converting
+static STATUS: AtomicU8 = AtomicU8::new(0);
+static DIO_OUT: AtomicU8 = AtomicU8::new(0);
+static RANGE1: AtomicU8 = AtomicU8::new(0);
+static RANGE2: AtomicU8 = AtomicU8::new(0);
+
+/// Error code of an operation on the unit `id`
+fn check(id: c_short) -> c_short {
+    match id {
+        0 if OPENED.load(Ordering::SeqCst) => 0,
+        _ => 5,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn TUSB0216AD_Device_Open(id: c_short) -> c_short {
+    match id {
+        0 if OPENED.swap(true, Ordering::SeqCst) => 3,
+        0 => 0,
+        _ => 5,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn TUSB0216AD_Device_Close(id: c_short) {
+    if id == 0 {
+        STATUS.store(0, Ordering::SeqCst);
+        OPENED.store(false, Ordering::SeqCst);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn TUSB0216AD_DIO_In(id: c_short, data: *mut c_uchar) -> c_short {
+    let code = check(id);
+    if code == 0 {
+        *data = 0b1010_0101;
+    }
+    code
+}
+
+#[no_mangle]
+pub extern "C" fn TUSB0216AD_DIO_Out(id: c_short, data: c_uchar) -> c_short {
+    let code = check(id);
+    if code == 0 {
+        DIO_OUT.store(data, Ordering::SeqCst);
+    }
+    code
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn TUSB0216AD_DIO_Chk(id: c_short, data: *mut c_uchar) -> c_short {
+    let code = check(id);
+    if code == 0 {
+        *data = DIO_OUT.load(Ordering::SeqCst);
+    }
+    code
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn TUSB0216AD_Ad_Single(id: c_short, data: *mut c_int) -> c_short {
+    match check(id) {
+        0 if STATUS.load(Ordering::SeqCst) != 0 => 11,
+        0 => {
+            *data = 0;
+            *data.add(1) = 65535;
+            0
+        }
+        code => code,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn TUSB0216AD_Start(
+    id: c_short,
+    ch: c_uchar,
+    _pre_len: c_int,
+    trig_type: c_uchar,
+    _trig_ch: c_uchar,
+) -> c_short {
+    match check(id) {
+        0 if ch > 2 || trig_type > 3 => 8,
+        0 => {
+            STATUS.store(3, Ordering::SeqCst);
+            0
+        }
+        code => code,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn TUSB0216AD_Stop(id: c_short) -> c_short {
+    let code = check(id);
+    if code == 0 {
+        STATUS.store(0, Ordering::SeqCst);
+    }
+    code
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn TUSB0216AD_Ad_Status(
+    id: c_short,
+    status: *mut c_uchar,
+    overflow: *mut c_uchar,
+    datalen: *mut c_uint,
+) -> c_short {
+    let code = check(id);
+    if code == 0 {
+        let converting = STATUS.load(Ordering::SeqCst);
+        *status = converting;
+        for ch in 0..2 {
+            *overflow.add(ch) = 0;
+            *datalen.add(ch) = if converting == 3 { DATALEN } else { 0 };
+        }
+    }
+    code
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn TUSB0216AD_Ad_Data(
+    id: c_short,
+    ch: c_uchar,
+    data: *mut c_int,
+    datalen: *mut c_uint,
+) -> c_short {
+    match check(id) {
+        0 if ch > 1 => 8,
+        0 => {
+            *datalen = (*datalen).min(DATALEN);
+            for i in 0..*datalen as usize {
+                *data.add(i) = match ch {
+                    0 => i as c_int,
+                    _ => 65535 - i as c_int,
+                };
+            }
+            0
+        }
+        code => code,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn TUSB0216AD_AdClk_Set(id: c_short, clk_time: c_int, sel: c_uchar) -> c_short {
+    match check(id) {
+        0 if clk_time < 500 || sel > 1 => 8,
+        code => code,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn TUSB0216AD_Level_Set(id: c_short, level: c_int, hys: c_short) -> c_short {
+    match check(id) {
+        0 if !(1..=65534).contains(&level) || !(0..=660).contains(&hys) => 8,
+        code => code,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn TUSB0216AD_Input_Set(id: c_short, type1: c_uchar, type2: c_uchar) -> c_short {
+    match check(id) {
+        0 if type1 > 6 || type2 > 6 => 8,
+        0 => {
+            RANGE1.store(type1, Ordering::SeqCst);
+            RANGE2.store(type2, Ordering::SeqCst);
+            0
+        }
+        code => code,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn TUSB0216AD_Input_Check(
+    id: c_short,
+    type1: *mut c_uchar,
+    type2: *mut c_uchar,
+) -> c_short {
+    let code = check(id);
+    if code == 0 {
+        *type1 = RANGE1.load(Ordering::SeqCst);
+        *type2 = RANGE2.load(Ordering::SeqCst);
+    }
+    code
+}
+
+#[no_mangle]
+pub extern "C" fn TUSB0216AD_Trigger(id: c_short) -> c_short {
+    check(id)
+}